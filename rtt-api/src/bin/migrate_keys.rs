@@ -0,0 +1,16 @@
+use anyhow::Result;
+use rtt_tdigest::StoreConfig;
+
+/// Rewrites digests stored under the old `app:agg_level:node[:dimension]` key,
+/// which kept only the latest window per node, to keys that include the
+/// window start, backfills the DynamoDB `series` attribute and rebuilds the
+/// DynamoDB catalog. Uses the same `RTT_STORE` configuration as the API.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let store = StoreConfig::from_env()?.open().await?;
+
+    let migrated = store.migrate_keys().await?;
+    println!("Migrated {} digests to the new key layout", migrated);
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use crate::request_id;
+use axum::{
+    extract::{FromRequestParts, Query},
+    http::{StatusCode, header::RETRY_AFTER, request::Parts},
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+/// Error returned by every handler, rendered as a JSON body
+#[derive(Debug)]
+pub enum ApiError {
+    /// Query string could not be parsed
+    InvalidQuery(String),
+    /// Query parameters parsed but are not acceptable
+    BadRequest(String),
+    /// The requested range or bucket count is above the allowed maximum
+    RangeTooLarge(String),
+    /// The requested app has no stored digests
+    NotFound(String),
+    /// The digest store could not answer
+    StoreUnavailable(String),
+    /// The digest store is shedding load, the request can be retried later
+    StoreThrottled(String),
+    /// The store answered with data or an error this service cannot handle
+    Internal(String),
+}
+
+impl ApiError {
+    /// Wrap a digest store failure
+    pub fn store(e: rtt_tdigest::Error) -> Self {
+        match e {
+            rtt_tdigest::Error::Throttled(_) => ApiError::StoreThrottled(e.to_string()),
+            rtt_tdigest::Error::Unavailable(_) => ApiError::StoreUnavailable(e.to_string()),
+            _ => ApiError::Internal(e.to_string()),
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidQuery(_) | ApiError::BadRequest(_) | ApiError::RangeTooLarge(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::StoreUnavailable(_) | ApiError::StoreThrottled(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidQuery(_) => "invalid_query",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::RangeTooLarge(_) => "range_too_large",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::StoreUnavailable(_) => "store_unavailable",
+            ApiError::StoreThrottled(_) => "store_throttled",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ApiError::InvalidQuery(m)
+            | ApiError::BadRequest(m)
+            | ApiError::RangeTooLarge(m)
+            | ApiError::NotFound(m)
+            | ApiError::StoreUnavailable(m)
+            | ApiError::StoreThrottled(m)
+            | ApiError::Internal(m) => m,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: &'a str,
+    request_id: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let request_id = request_id::current();
+        eprintln!(
+            "[{}] {} {}: {}",
+            request_id,
+            self.status().as_u16(),
+            self.code(),
+            self.message()
+        );
+
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.message(),
+            request_id,
+        };
+
+        match self {
+            // DynamoDB throttling usually clears within seconds
+            ApiError::StoreThrottled(_) => {
+                (self.status(), [(RETRY_AFTER, "1")], Json(body)).into_response()
+            }
+            _ => (self.status(), Json(body)).into_response(),
+        }
+    }
+}
+
+/// `Query` extractor that rejects malformed query strings with an `ApiError`
+/// instead of axum's plain text response
+pub struct ApiQuery<T>(pub T);
+
+impl<T, S> FromRequestParts<S> for ApiQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Query::<T>::from_request_parts(parts, state).await {
+            Ok(Query(value)) => Ok(ApiQuery(value)),
+            Err(rejection) => Err(ApiError::InvalidQuery(rejection.body_text())),
+        }
+    }
+}
@@ -1,25 +1,42 @@
-use ::aws_sdk_dynamodb::Client;
+mod error;
+mod quantiles;
+mod request_id;
+mod series;
+
 use anyhow::Result;
-use aws_config::{from_env, meta::region::RegionProviderChain};
 use axum::{
     Router,
-    extract::{Json, Query, State},
-    http::StatusCode,
+    extract::{Json, Path, State},
+    middleware,
     routing::get,
 };
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
+use error::{ApiError, ApiQuery};
+use quantiles::Quantiles;
+use rtt_tdigest::{DigestQuery, Listing, MAX_QUERY_NODES, StoreConfig, metric};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::env;
 use std::net::SocketAddr;
-use std::usize;
 use tdigest::TDigest;
 use tokio::net::TcpListener;
 
+/// Longest time range a single quantile request may cover
+const MAX_RANGE: TimeDelta = TimeDelta::days(92);
+
+/// App queried when a request names none and `RTT_APP` is unset, the same
+/// default the collector stores under
+const DEFAULT_APP: &str = "sample-app";
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let svc = tdigest_svc().await;
+    let svc = tdigest_svc().await?;
     let rtr = Router::new()
         .route("/quantiles", get(get_quantiles))
+        .route("/quantiles/series", get(get_quantiles_series))
+        .route("/apps", get(get_apps))
+        .route("/apps/{app}/nodes", get(get_app_nodes))
+        .route("/apps/{app}/dimensions", get(get_app_dimensions))
+        .layer(middleware::from_fn(request_id::middleware))
         .with_state(svc);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
@@ -31,77 +48,337 @@ async fn main() -> Result<()> {
     Ok(())
 }
 async fn get_quantiles(
-    Query(q): Query<QuantilesRequest>,
+    ApiQuery(q): ApiQuery<QuantilesRequest>,
     State(svc): State<rtt_tdigest::Service>,
-) -> Result<Json<QuantilesResponse>, StatusCode> {
-    println!("[GET] /quantiles from: {}, to: {}", q.from, q.to);
+) -> Result<Json<QuantilesResponse>, ApiError> {
+    println!(
+        "[{}] [GET] /quantiles {:?}, q: {:?}",
+        request_id::current(),
+        q.selector,
+        q.q
+    );
 
-    let tdigests = match svc.query_digests("1m", q.from, q.to).await {
-        Ok(d) => d,
-        Err(e) => {
-            eprintln!("Error querying digests: {}", e);
+    q.selector.validate()?;
+    let unit = q.selector.unit()?;
+    let quantile_list = quantiles::parse_quantiles(q.q.as_deref()).map_err(ApiError::BadRequest)?;
 
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let query = q.selector.digest_query(svc.app());
+    let tdigests = svc
+        .query_digests(&query)
+        .await
+        .map_err(ApiError::store)?
+        .into_iter()
+        .map(|record| record.tdigest)
+        .collect::<Vec<TDigest>>();
 
     match tdigests.len() {
-        0 => Ok(Json(QuantilesResponse {
-            agg_level: "1m".to_string(),
-            sample_count: 0,
-            quantiles: HashMap::new(),
-        })),
+        0 => {
+            ensure_app_exists(&svc, &query.app).await?;
+
+            Ok(Json(QuantilesResponse {
+                app: query.app,
+                agg_level: query.agg_level,
+                metric: query.metric,
+                sample_count: 0,
+                unit,
+                quantiles: Quantiles::default(),
+            }))
+        }
         _ => {
             let merged = TDigest::merge_digests(tdigests);
-            let quantiles = HashMap::from([
-                (
-                    "p99".to_string(),
-                    format!("{:.3}", merged.estimate_quantile(0.99)),
-                ),
-                (
-                    "p95".to_string(),
-                    format!("{:.3}", merged.estimate_quantile(0.95)),
-                ),
-                (
-                    "p90".to_string(),
-                    format!("{:.3}", merged.estimate_quantile(0.90)),
-                ),
-                (
-                    "p75".to_string(),
-                    format!("{:.3}", merged.estimate_quantile(0.75)),
-                ),
-                (
-                    "p50".to_string(),
-                    format!("{:.3}", merged.estimate_quantile(0.50)),
-                ),
-            ]);
 
             Ok(Json(QuantilesResponse {
-                agg_level: "1m".to_string(),
+                app: query.app,
+                agg_level: query.agg_level,
+                metric: query.metric,
                 sample_count: merged.count() as usize,
-                quantiles,
+                unit,
+                quantiles: quantiles::estimate(&merged, &quantile_list),
             }))
         }
     }
 }
 
-async fn tdigest_svc() -> rtt_tdigest::Service {
-    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
-    let config = from_env().region(region_provider).load().await;
-    let client = Client::new(&config);
+async fn get_quantiles_series(
+    ApiQuery(q): ApiQuery<SeriesRequest>,
+    State(svc): State<rtt_tdigest::Service>,
+) -> Result<Json<SeriesResponse>, ApiError> {
+    println!(
+        "[{}] [GET] /quantiles/series {:?}, step: {}, q: {:?}",
+        request_id::current(),
+        q.selector,
+        q.step,
+        q.q
+    );
+
+    q.selector.validate()?;
+    let unit = q.selector.unit()?;
+    let quantile_list = quantiles::parse_quantiles(q.q.as_deref()).map_err(ApiError::BadRequest)?;
+    let step = series::parse_step(&q.step).map_err(ApiError::BadRequest)?;
+
+    let buckets = (q.selector.to - q.selector.from).num_seconds() / step.num_seconds();
+    if buckets > series::MAX_BUCKETS {
+        return Err(ApiError::RangeTooLarge(format!(
+            "range spans {} buckets of {}, at most {} are allowed",
+            buckets,
+            q.step,
+            series::MAX_BUCKETS
+        )));
+    }
 
-    rtt_tdigest::Service::new(client, "sample-app".to_string(), "local".to_string())
+    let query = q.selector.digest_query(svc.app());
+    let records = svc.query_digests(&query).await.map_err(ApiError::store)?;
+    if records.is_empty() {
+        ensure_app_exists(&svc, &query.app).await?;
+    }
+
+    let points = series::bucket(records, step)
+        .into_iter()
+        .map(|bucket| SeriesPoint {
+            ts: bucket.ts,
+            count: bucket.count,
+            quantiles: quantiles::estimate(&bucket.digest, &quantile_list),
+            partial: bucket.partial,
+            dropped: bucket.dropped,
+        })
+        .collect();
+
+    Ok(Json(SeriesResponse {
+        app: query.app,
+        agg_level: query.agg_level,
+        metric: query.metric,
+        step: q.step,
+        unit,
+        series: points,
+    }))
 }
 
-#[derive(Deserialize)]
-struct QuantilesRequest {
+async fn get_apps(State(svc): State<rtt_tdigest::Service>) -> Result<Json<AppsResponse>, ApiError> {
+    println!("[{}] [GET] /apps", request_id::current());
+
+    let apps = svc.list_apps().await.map_err(ApiError::store)?;
+
+    Ok(Json(AppsResponse { apps }))
+}
+
+async fn get_app_nodes(
+    Path(app): Path<String>,
+    State(svc): State<rtt_tdigest::Service>,
+) -> Result<Json<NodesResponse>, ApiError> {
+    println!("[{}] [GET] /apps/{}/nodes", request_id::current(), app);
+
+    // every app with stored digests has at least one node
+    let nodes = svc.list_nodes(&app).await.map_err(ApiError::store)?;
+    if nodes.is_empty() {
+        return Err(unknown_app(&app));
+    }
+
+    Ok(Json(NodesResponse { app, nodes }))
+}
+
+async fn get_app_dimensions(
+    Path(app): Path<String>,
+    State(svc): State<rtt_tdigest::Service>,
+) -> Result<Json<DimensionsResponse>, ApiError> {
+    println!("[{}] [GET] /apps/{}/dimensions", request_id::current(), app);
+
+    let dimensions = svc.list_dimensions(&app).await.map_err(ApiError::store)?;
+    if dimensions.is_empty() {
+        ensure_app_exists(&svc, &app).await?;
+    }
+
+    Ok(Json(DimensionsResponse { app, dimensions }))
+}
+
+/// Distinguish an app without digests in a range from one that never stored any
+async fn ensure_app_exists(svc: &rtt_tdigest::Service, app: &str) -> Result<(), ApiError> {
+    match svc.app_exists(app).await.map_err(ApiError::store)? {
+        true => Ok(()),
+        false => Err(unknown_app(app)),
+    }
+}
+
+fn unknown_app(app: &str) -> ApiError {
+    ApiError::NotFound(format!("no digests stored for app {:?}", app))
+}
+
+async fn tdigest_svc() -> Result<rtt_tdigest::Service> {
+    let store = StoreConfig::from_env()?.open().await?;
+    let app = env::var("RTT_APP").unwrap_or_else(|_| DEFAULT_APP.to_string());
+
+    // the API never stores a digest, so the service needs no node
+    Ok(rtt_tdigest::Service::new(store, app, String::new()))
+}
+
+/// Digests a request reads, shared by the quantile endpoints
+#[derive(Debug, Deserialize)]
+struct Selector {
     from: DateTime<Utc>,
     to: DateTime<Utc>,
+    /// Defaults to the app the API was started for
+    app: Option<String>,
+    /// Comma separated node ids, digests of every node are merged when unset
+    node: Option<String>,
+    /// Defaults to `1m`
+    agg_level: Option<String>,
+    dimension: Option<String>,
+    /// Defaults to `srtt`
+    metric: Option<String>,
+}
+
+impl Selector {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.from > self.to {
+            return Err(ApiError::BadRequest(format!(
+                "from ({}) is after to ({})",
+                self.from, self.to
+            )));
+        }
+        if self.nodes().len() > MAX_QUERY_NODES {
+            return Err(ApiError::BadRequest(format!(
+                "more than {} nodes requested",
+                MAX_QUERY_NODES
+            )));
+        }
+        if self.to - self.from > MAX_RANGE {
+            return Err(ApiError::RangeTooLarge(format!(
+                "range covers more than {} days",
+                MAX_RANGE.num_days()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Unit of the selected metric's values, rejecting unknown metrics
+    fn unit(&self) -> Result<&'static str, ApiError> {
+        let name = self.metric.as_deref().unwrap_or(metric::SRTT);
+
+        metric::unit(name).ok_or_else(|| ApiError::BadRequest(format!("unknown metric {:?}", name)))
+    }
+
+    /// Node ids of the comma separated `node` parameter
+    fn nodes(&self) -> Vec<String> {
+        self.node
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|node| !node.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn digest_query(&self, default_app: &str) -> DigestQuery {
+        DigestQuery {
+            app: self.app.as_deref().unwrap_or(default_app).to_string(),
+            agg_level: self.agg_level.as_deref().unwrap_or("1m").to_string(),
+            metric: self.metric.as_deref().unwrap_or(metric::SRTT).to_string(),
+            nodes: self.nodes(),
+            dimension: self.dimension.clone(),
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct QuantilesRequest {
+    #[serde(flatten)]
+    selector: Selector,
+    /// Comma separated quantiles, e.g. `0.5,0.999`
+    q: Option<String>,
 }
 
 #[derive(Serialize)]
 struct QuantilesResponse {
+    app: String,
     agg_level: String,
+    metric: String,
     sample_count: usize,
-    quantiles: HashMap<String, String>,
+    unit: &'static str,
+    quantiles: Quantiles,
+}
+
+#[derive(Deserialize)]
+struct SeriesRequest {
+    #[serde(flatten)]
+    selector: Selector,
+    /// Bucket width, e.g. `1m`, `5m`, `1h`
+    step: String,
+    /// Comma separated quantiles, e.g. `0.5,0.999`
+    q: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SeriesResponse {
+    app: String,
+    agg_level: String,
+    metric: String,
+    step: String,
+    unit: &'static str,
+    series: Vec<SeriesPoint>,
+}
+
+#[derive(Serialize)]
+struct SeriesPoint {
+    ts: DateTime<Utc>,
+    count: u64,
+    quantiles: Quantiles,
+    partial: bool,
+    dropped: u64,
+}
+
+#[derive(Serialize)]
+struct AppsResponse {
+    apps: Vec<Listing>,
+}
+
+#[derive(Serialize)]
+struct NodesResponse {
+    app: String,
+    nodes: Vec<Listing>,
+}
+
+#[derive(Serialize)]
+struct DimensionsResponse {
+    app: String,
+    dimensions: Vec<Listing>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector(node: Option<String>) -> Selector {
+        Selector {
+            from: "2023-06-01T00:00:00Z".parse().unwrap(),
+            to: "2023-06-02T00:00:00Z".parse().unwrap(),
+            app: None,
+            node,
+            agg_level: None,
+            dimension: None,
+            metric: None,
+        }
+    }
+
+    #[test]
+    fn nodes_are_split_and_trimmed() {
+        assert!(selector(None).nodes().is_empty());
+        assert_eq!(
+            selector(Some(" web-1,,web-2 ".to_string())).nodes(),
+            ["web-1", "web-2"]
+        );
+    }
+
+    #[test]
+    fn node_lists_are_capped() {
+        let nodes = |n: usize| (0..n).map(|i| format!("node-{i}")).collect::<Vec<_>>();
+
+        let most = selector(Some(nodes(MAX_QUERY_NODES).join(",")));
+        assert!(most.validate().is_ok());
+
+        let too_many = selector(Some(nodes(MAX_QUERY_NODES + 1).join(",")));
+        assert!(matches!(too_many.validate(), Err(ApiError::BadRequest(_))));
+    }
 }
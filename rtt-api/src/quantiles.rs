@@ -0,0 +1,157 @@
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use tdigest::TDigest;
+
+/// Quantiles returned when the request does not list any
+pub const DEFAULT_QUANTILES: [f64; 5] = [0.5, 0.75, 0.9, 0.95, 0.99];
+
+/// Upper bound on the number of quantiles a single request may ask for
+pub const MAX_QUANTILES: usize = 20;
+
+/// Parse a comma separated list of quantiles such as `0.5,0.999,0.9999`,
+/// sorted ascending with repeats removed. Distinct quantiles sharing a label
+/// (e.g. 0.9999999 and 1 are both `p100`) are rejected.
+pub fn parse_quantiles(list: Option<&str>) -> Result<Vec<f64>, String> {
+    let Some(list) = list else {
+        return Ok(DEFAULT_QUANTILES.to_vec());
+    };
+
+    let mut quantiles = list
+        .split(',')
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+        .map(|q| match q.parse::<f64>() {
+            Ok(v) if (0.0..=1.0).contains(&v) => Ok(v),
+            _ => Err(format!(
+                "invalid quantile {:?}, expected a number in 0..=1",
+                q
+            )),
+        })
+        .collect::<Result<Vec<f64>, String>>()?;
+    match quantiles.len() {
+        0 => return Err("q must list at least one quantile".to_string()),
+        n if n > MAX_QUANTILES => {
+            return Err(format!(
+                "q lists {} quantiles, at most {} are allowed",
+                n, MAX_QUANTILES
+            ));
+        }
+        _ => {}
+    }
+
+    quantiles.sort_by(f64::total_cmp);
+    quantiles.dedup();
+    if let Some(pair) = quantiles.windows(2).find(|p| label(p[0]) == label(p[1])) {
+        return Err(format!(
+            "quantiles {} and {} are both reported as {}",
+            pair[0],
+            pair[1],
+            label(pair[0])
+        ));
+    }
+
+    Ok(quantiles)
+}
+
+/// Estimates keyed by their label e.g. `p99.9`, serialized as a JSON object
+/// in the order of the quantiles they were estimated for
+#[derive(Debug, Default)]
+pub struct Quantiles(Vec<(String, f64)>);
+
+impl Serialize for Quantiles {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (label, value) in &self.0 {
+            map.serialize_entry(label, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Estimate each quantile from the digest, see `Quantiles`
+pub fn estimate(digest: &TDigest, quantiles: &[f64]) -> Quantiles {
+    Quantiles(
+        quantiles
+            .iter()
+            .map(|&q| (label(q), digest.estimate_quantile(q)))
+            .collect(),
+    )
+}
+
+/// Percentile label for a quantile, 0.999 -> `p99.9`
+fn label(q: f64) -> String {
+    let percentile = format!("{:.4}", q * 100.0);
+    let percentile = percentile.trim_end_matches('0').trim_end_matches('.');
+
+    format!("p{}", percentile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_when_unset() {
+        assert_eq!(parse_quantiles(None).unwrap(), DEFAULT_QUANTILES);
+    }
+
+    #[test]
+    fn parses_a_list_skipping_blanks() {
+        assert_eq!(
+            parse_quantiles(Some(" 0.5, 0.999,,1")).unwrap(),
+            [0.5, 0.999, 1.0]
+        );
+        assert_eq!(parse_quantiles(Some("0")).unwrap(), [0.0]);
+    }
+
+    #[test]
+    fn sorts_and_drops_repeats() {
+        assert_eq!(
+            parse_quantiles(Some("0.99,0.5,0.99,0.9")).unwrap(),
+            [0.5, 0.9, 0.99]
+        );
+    }
+
+    #[test]
+    fn rejects_quantiles_sharing_a_label() {
+        assert!(parse_quantiles(Some("0.9999999,1")).is_err());
+        assert!(parse_quantiles(Some("0.5,0.5000001")).is_err());
+    }
+
+    #[test]
+    fn estimates_serialize_in_quantile_order() {
+        let digest = TDigest::new_with_size(100).merge_unsorted((1..=100).map(f64::from).collect());
+        let quantiles = parse_quantiles(Some("0.99,0.5,0.9,0.75")).unwrap();
+
+        let json = serde_json::to_string(&estimate(&digest, &quantiles)).unwrap();
+        let labels: Vec<_> = ["p50", "p75", "p90", "p99"]
+            .iter()
+            .map(|label| json.find(label).unwrap())
+            .collect();
+        assert!(labels.is_sorted(), "{json}");
+    }
+
+    #[test]
+    fn rejects_invalid_lists() {
+        for list in ["", ",", "1.5", "-0.1", "p99", "0.5,NaN"] {
+            assert!(
+                parse_quantiles(Some(list)).is_err(),
+                "{list:?} was accepted"
+            );
+        }
+
+        let too_many = vec!["0.5"; MAX_QUANTILES + 1].join(",");
+        assert!(parse_quantiles(Some(&too_many)).is_err());
+        let most = vec!["0.5"; MAX_QUANTILES].join(",");
+        assert!(parse_quantiles(Some(&most)).is_ok());
+    }
+
+    #[test]
+    fn labels_trim_trailing_zeros() {
+        assert_eq!(label(0.5), "p50");
+        assert_eq!(label(0.99), "p99");
+        assert_eq!(label(0.999), "p99.9");
+        assert_eq!(label(0.9999), "p99.99");
+        assert_eq!(label(0.0), "p0");
+        assert_eq!(label(1.0), "p100");
+    }
+}
@@ -0,0 +1,148 @@
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use rtt_tdigest::TDigestRecord;
+use std::collections::BTreeMap;
+use tdigest::TDigest;
+
+/// Upper bound on the number of buckets a single series request may span
+pub const MAX_BUCKETS: i64 = 10_000;
+
+/// Parse a bucket width such as `1m`, `5m`, `1h` or `1d`. Digests cover whole
+/// minutes so the step must be too.
+pub fn parse_step(step: &str) -> Result<TimeDelta, String> {
+    let invalid = || format!("invalid step {:?}, expected e.g. 1m, 5m, 1h or 1d", step);
+
+    let split = step
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (count, unit) = step.split_at(split);
+    let count = count.parse::<i64>().map_err(|_| invalid())?;
+
+    let delta = match unit {
+        "m" => TimeDelta::try_minutes(count),
+        "h" => TimeDelta::try_hours(count),
+        "d" => TimeDelta::try_days(count),
+        _ => None,
+    };
+
+    match delta {
+        Some(delta) if delta > TimeDelta::zero() => Ok(delta),
+        _ => Err(invalid()),
+    }
+}
+
+/// Digests merged within one bucket
+pub struct Bucket {
+    pub ts: DateTime<Utc>,
+    pub count: u64,
+    pub digest: TDigest,
+    /// Whether any merged record covers a window cut short
+    pub partial: bool,
+    /// Samples the collectors lost in the bucket's windows
+    pub dropped: u64,
+}
+
+/// Group records into buckets of `step` aligned to the unix epoch, merging the
+/// digests within each bucket. Buckets without records are omitted.
+pub fn bucket(records: Vec<TDigestRecord>, step: TimeDelta) -> Vec<Bucket> {
+    let mut grouped: BTreeMap<DateTime<Utc>, Vec<TDigestRecord>> = BTreeMap::new();
+    for record in records {
+        let ts = record
+            .created_at
+            .duration_trunc(step)
+            .unwrap_or(record.created_at);
+        grouped.entry(ts).or_default().push(record);
+    }
+
+    grouped
+        .into_iter()
+        .map(|(ts, records)| Bucket {
+            ts,
+            count: records.iter().map(|r| r.count).sum(),
+            partial: records.iter().any(|r| r.partial),
+            dropped: records.iter().map(|r| r.dropped).sum(),
+            digest: TDigest::merge_digests(records.into_iter().map(|r| r.tdigest).collect()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn record(created_at: DateTime<Utc>, values: &[f64], partial: bool) -> TDigestRecord {
+        let tdigest = TDigest::new_with_size(100).merge_unsorted(values.to_vec());
+
+        TDigestRecord {
+            dropped: 1,
+            ..TDigestRecord::new(
+                "app",
+                "1m",
+                "node",
+                created_at,
+                None,
+                tdigest,
+                values.len() as u64,
+            )
+        }
+        .with_partial(partial)
+    }
+
+    #[test]
+    fn parse_step_accepts_minutes_hours_and_days() {
+        assert_eq!(parse_step("1m").unwrap(), TimeDelta::minutes(1));
+        assert_eq!(parse_step("15m").unwrap(), TimeDelta::minutes(15));
+        assert_eq!(parse_step("1h").unwrap(), TimeDelta::hours(1));
+        assert_eq!(parse_step("7d").unwrap(), TimeDelta::days(7));
+    }
+
+    #[test]
+    fn parse_step_rejects_other_widths() {
+        for step in ["", "m", "5", "0m", "-5m", "30s", "1w", "1.5h"] {
+            assert!(parse_step(step).is_err(), "{step:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn buckets_align_to_the_step_and_merge() {
+        let at = |h, m| Utc.with_ymd_and_hms(2023, 6, 1, h, m, 0).unwrap();
+        let records = vec![
+            record(at(10, 7), &[1.0, 2.0], false),
+            record(at(10, 2), &[3.0], true),
+            record(at(10, 14), &[4.0, 5.0, 6.0], false),
+            record(at(11, 0), &[7.0], false),
+        ];
+
+        let buckets = bucket(records, TimeDelta::minutes(10));
+
+        let starts: Vec<_> = buckets.iter().map(|b| b.ts).collect();
+        assert_eq!(starts, [at(10, 0), at(10, 10), at(11, 0)]);
+        assert_eq!(buckets[0].count, 3);
+        assert_eq!(buckets[0].digest.count(), 3.0);
+        assert_eq!(buckets[0].digest.max(), 3.0);
+        assert!(buckets[0].partial);
+        assert_eq!(buckets[0].dropped, 2);
+        assert_eq!(buckets[1].count, 3);
+        assert!(!buckets[1].partial);
+        assert_eq!(buckets[2].count, 1);
+    }
+
+    #[test]
+    fn partial_and_restarted_records_of_a_window_merge() {
+        let at = Utc.with_ymd_and_hms(2023, 6, 1, 10, 0, 0).unwrap();
+        // stored on shutdown and by the restarted collector for the same window
+        let records = vec![record(at, &[1.0, 2.0], true), record(at, &[3.0], false)];
+
+        let buckets = bucket(records, TimeDelta::minutes(1));
+
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].count, 3);
+        assert_eq!(buckets[0].digest.count(), 3.0);
+        assert!(buckets[0].partial);
+    }
+
+    #[test]
+    fn no_records_make_no_buckets() {
+        assert!(bucket(Vec::new(), TimeDelta::minutes(1)).is_empty());
+    }
+}
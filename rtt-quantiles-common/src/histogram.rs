@@ -0,0 +1,121 @@
+//! Log-linear histogram layout shared by the eBPF program, which counts
+//! samples per bucket, and userspace, which turns buckets back into values.
+//!
+//! Values below `SUB_BUCKETS` get a bucket each. Above that every power of two
+//! is split into `SUB_BUCKETS` equal buckets, so a bucket's width is at most
+//! 1/16 of its lower bound.
+
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u32 = 1 << SUB_BUCKET_BITS;
+
+/// Number of buckets needed to cover every `u32`
+pub const BUCKETS: u32 = SUB_BUCKETS + (32 - SUB_BUCKET_BITS) * SUB_BUCKETS;
+
+/// Bucket `value` falls into
+#[inline(always)]
+pub fn bucket(value: u32) -> u32 {
+    if value < SUB_BUCKETS {
+        return value;
+    }
+
+    let shift = log2(value) - SUB_BUCKET_BITS;
+    let offset = (value >> shift) - SUB_BUCKETS;
+    SUB_BUCKETS + shift * SUB_BUCKETS + offset
+}
+
+/// Smallest and largest value counted in `bucket`
+pub fn bounds(bucket: u32) -> (u32, u32) {
+    if bucket < SUB_BUCKETS {
+        return (bucket, bucket);
+    }
+
+    let shift = (bucket - SUB_BUCKETS) / SUB_BUCKETS;
+    let offset = (bucket - SUB_BUCKETS) % SUB_BUCKETS;
+    let low = (u64::from(SUB_BUCKETS + offset)) << shift;
+    let high = low + (1 << shift) - 1;
+    (low as u32, high as u32)
+}
+
+/// Index of the highest set bit. Spelled out as comparisons since BPF has no
+/// count leading zeros instruction.
+#[inline(always)]
+fn log2(mut value: u32) -> u32 {
+    let mut log = 0;
+    if value >= 1 << 16 {
+        value >>= 16;
+        log += 16;
+    }
+    if value >= 1 << 8 {
+        value >>= 8;
+        log += 8;
+    }
+    if value >= 1 << 4 {
+        value >>= 4;
+        log += 4;
+    }
+    if value >= 1 << 2 {
+        value >>= 2;
+        log += 2;
+    }
+    if value >= 1 << 1 {
+        log += 1;
+    }
+    log
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values_get_a_bucket_each() {
+        for value in 0..SUB_BUCKETS {
+            assert_eq!(bucket(value), value);
+            assert_eq!(bounds(value), (value, value));
+        }
+    }
+
+    #[test]
+    fn extremes_fit_the_bucket_count() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(u32::MAX), BUCKETS - 1);
+        assert_eq!(bounds(BUCKETS - 1).1, u32::MAX);
+    }
+
+    #[test]
+    fn every_value_falls_within_its_bucket_bounds() {
+        let values = (0..4096).chain([
+            65_535,
+            65_536,
+            1 << 31,
+            (1 << 31) - 1,
+            u32::MAX - 1,
+            u32::MAX,
+        ]);
+        for value in values {
+            let (low, high) = bounds(bucket(value));
+            assert!(
+                low <= value && value <= high,
+                "{value} not in {low}..={high}"
+            );
+        }
+    }
+
+    #[test]
+    fn buckets_are_contiguous() {
+        for b in 1..BUCKETS {
+            let (low, _) = bounds(b);
+            let (_, previous_high) = bounds(b - 1);
+            assert_eq!(low, previous_high + 1, "gap before bucket {b}");
+            assert_eq!(bucket(low), b);
+        }
+    }
+
+    #[test]
+    fn width_is_at_most_a_sixteenth_of_the_lower_bound() {
+        for b in SUB_BUCKETS..BUCKETS {
+            let (low, high) = bounds(b);
+            assert!(u64::from(high - low + 1) * u64::from(SUB_BUCKETS) <= u64::from(low));
+        }
+    }
+}
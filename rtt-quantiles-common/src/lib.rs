@@ -0,0 +1,158 @@
+#![no_std]
+
+use core::{
+    mem::{offset_of, size_of},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+pub mod histogram;
+pub mod sampling;
+
+pub const AF_INET: u16 = 2;
+pub const AF_INET6: u16 = 10;
+
+/// Values of the eBPF program's `MODE` global, set when it is loaded.
+/// Every sample is sent to userspace as an `RttEvent`.
+pub const MODE_EVENTS: u8 = 0;
+/// Samples are only counted in the `HISTOGRAM` map.
+pub const MODE_HISTOGRAM: u8 = 1;
+
+/// Settings written by userspace to the single entry of the `CONFIG` map
+/// before the program is attached
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KernelConfig {
+    /// Minimum time between two samples of the same connection, 0 keeps every
+    /// sample
+    pub sample_interval_ns: u64,
+    /// `FILTER_*` bits set for the allow lists that are in use, empty allow
+    /// lists allow everything
+    pub filter_flags: u64,
+    /// Non-zero to send every event with `RttEvent::metrics` filled, zero to
+    /// send the `RttEvent::SHORT_SIZE` prefix only
+    pub tcp_metrics: u64,
+}
+
+/// Destinations are only collected if they match `ALLOW_V4`/`ALLOW_V6`
+pub const FILTER_ALLOW_DESTINATIONS: u64 = 1 << 0;
+/// Destinations are only collected if their port is in `ALLOW_PORTS`
+pub const FILTER_ALLOW_PORTS: u64 = 1 << 1;
+
+/// Capacity of each filter map
+pub const MAX_FILTER_ENTRIES: u32 = 1024;
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KernelConfig {}
+
+/// Bumped whenever the layout of an event written to the ring buffer changes.
+pub const EVENT_VERSION: u16 = 3;
+
+/// Set in `RttEvent::flags` when `RttEvent::metrics` is filled
+pub const EVENT_TCP_METRICS: u32 = 1 << 0;
+
+/// Leads every ring buffer record so userspace can reject records written by
+/// a mismatched eBPF object instead of reinterpreting them. `size` is the
+/// length of the record, see `RttEvent::SHORT_SIZE`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct EventHeader {
+    pub version: u16,
+    pub size: u16,
+}
+
+impl EventHeader {
+    pub const fn new<T>() -> Self {
+        Self::sized(size_of::<T>())
+    }
+
+    pub const fn sized(size: usize) -> Self {
+        Self {
+            version: EVENT_VERSION,
+            size: size as u16,
+        }
+    }
+}
+
+/// Addresses are stored in network byte order. IPv4 addresses occupy the first
+/// four bytes of each array, the remainder is zeroed. Ports are in host byte order.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RttEvent {
+    pub header: EventHeader,
+    pub srtt_us: u32,
+    pub family: u16,
+    pub protocol: u16,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub src_addr: [u8; 16],
+    pub dst_addr: [u8; 16],
+    /// `EVENT_*` bits
+    pub flags: u32,
+    pub metrics: TcpMetrics,
+}
+
+/// `tcp_sock` fields read besides `srtt_us` when `KernelConfig::tcp_metrics`
+/// is set
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TcpMetrics {
+    /// Mean deviation of the rtt
+    pub mdev_us: u32,
+    /// Minimum rtt over the kernel's recent window, `u32::MAX` until measured
+    pub rtt_min_us: u32,
+    pub snd_cwnd: u32,
+    /// Segments retransmitted since the connection was established, a running
+    /// total rather than the retransmits since the previous sample
+    pub total_retrans: u32,
+    pub packets_out: u32,
+    /// Segment size, turns `rate_delivered` into bytes
+    pub mss_cache: u32,
+    /// Segments delivered over the last `rate_interval_us`
+    pub rate_delivered: u32,
+    pub rate_interval_us: u32,
+}
+
+impl RttEvent {
+    /// Size of the record on the wire, checked against the struct at compile time
+    pub const SIZE: usize = 84;
+    /// Size of a record without the trailing `metrics`, sent when
+    /// `KernelConfig::tcp_metrics` is off
+    pub const SHORT_SIZE: usize = 52;
+
+    /// The record without `metrics`, see `SHORT_SIZE`
+    pub fn short(&self) -> &[u8; Self::SHORT_SIZE] {
+        // SAFETY: repr(C) and `metrics` starts at SHORT_SIZE, so the prefix
+        // is in bounds and made of plain integers without padding
+        unsafe { &*(self as *const Self as *const [u8; Self::SHORT_SIZE]) }
+    }
+
+    /// Local end of the connection
+    pub fn src(&self) -> SocketAddr {
+        SocketAddr::new(to_ip(self.family, self.src_addr), self.src_port)
+    }
+
+    /// Remote end of the connection
+    pub fn dst(&self) -> SocketAddr {
+        SocketAddr::new(to_ip(self.family, self.dst_addr), self.dst_port)
+    }
+
+    /// Extra metrics, if the probe was configured to read them
+    pub fn metrics(&self) -> Option<&TcpMetrics> {
+        (self.flags & EVENT_TCP_METRICS != 0).then_some(&self.metrics)
+    }
+}
+
+const _: () = assert!(size_of::<RttEvent>() == RttEvent::SIZE);
+const _: () = assert!(offset_of!(RttEvent, metrics) == RttEvent::SHORT_SIZE);
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RttEvent {}
+
+/// Decodes a network byte order address captured by the probe. IPv4-mapped
+/// IPv6 addresses (dual-stack sockets talking to v4 peers) render as IPv4.
+fn to_ip(family: u16, addr: [u8; 16]) -> IpAddr {
+    match family {
+        AF_INET => IpAddr::V4(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3])),
+        _ => IpAddr::V6(Ipv6Addr::from(addr)).to_canonical(),
+    }
+}
@@ -0,0 +1,42 @@
+//! Per-connection rate limiting of the eBPF program, see
+//! `KernelConfig::sample_interval_ns`
+
+/// Whether a connection last sampled at `last`, if ever, is due for another
+/// sample at `now`, both in ns since boot. Samples are at least `interval` ns
+/// apart, 0 keeps every sample.
+pub fn due(last: Option<u64>, now: u64, interval: u64) -> bool {
+    match last {
+        Some(last) if interval > 0 => now.wrapping_sub(last) >= interval,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_samples_are_always_due() {
+        assert!(due(None, 0, 1_000));
+        assert!(due(None, 5_000, 1_000));
+    }
+
+    #[test]
+    fn samples_are_an_interval_apart() {
+        assert!(!due(Some(5_000), 5_000, 1_000));
+        assert!(!due(Some(5_000), 5_999, 1_000));
+        assert!(due(Some(5_000), 6_000, 1_000));
+        assert!(due(Some(5_000), 60_000, 1_000));
+    }
+
+    #[test]
+    fn zero_interval_keeps_every_sample() {
+        assert!(due(Some(5_000), 5_000, 0));
+    }
+
+    #[test]
+    fn clock_wrapping_is_tolerated() {
+        assert!(!due(Some(u64::MAX - 100), 50, 1_000));
+        assert!(due(Some(u64::MAX - 100), 900, 1_000));
+    }
+}
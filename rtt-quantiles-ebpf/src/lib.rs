@@ -1,3 +1,11 @@
 #![no_std]
 
+#[allow(
+    clippy::all,
+    dead_code,
+    non_camel_case_types,
+    non_snake_case,
+    non_upper_case_globals,
+    unnecessary_transmutes
+)]
 pub mod vmlinux;
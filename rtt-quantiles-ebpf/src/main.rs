@@ -2,62 +2,224 @@
 #![no_main]
 
 use aya_ebpf::{
-    helpers::bpf_probe_read_kernel,
+    helpers::{bpf_ktime_get_ns, bpf_probe_read_kernel},
     macros::{fentry, map},
-    maps::RingBuf,
+    maps::{lpm_trie::Key, Array, HashMap, LpmTrie, LruHashMap, PerCpuArray, RingBuf},
     programs::FEntryContext,
 };
-use aya_log_ebpf::info;
+use core::ptr;
+
+use rtt_quantiles_common::{
+    histogram, sampling, EventHeader, KernelConfig, RttEvent, TcpMetrics, AF_INET, AF_INET6,
+    EVENT_TCP_METRICS, FILTER_ALLOW_DESTINATIONS, FILTER_ALLOW_PORTS, MAX_FILTER_ENTRIES,
+    MODE_HISTOGRAM,
+};
 use rtt_quantiles_ebpf::vmlinux::{sock, tcp_sock};
 
+/// Set by userspace when loading the program, one of the `MODE_*` constants
+#[no_mangle]
+static MODE: u8 = 0;
+
+/// Written by userspace before attaching, see `KernelConfig`
+#[map(name = "CONFIG")]
+static CONFIG: Array<KernelConfig> = Array::with_max_entries(1, 0);
+
+/// Time in ns of the last sample taken per connection, keyed by socket
+/// pointer. Closed connections are never removed, the LRU evicts them.
+#[map(name = "LAST_SAMPLE")]
+static LAST_SAMPLE: LruHashMap<u64, u64> = LruHashMap::with_max_entries(65536, 0);
+
+/// Destination networks and ports samples are collected or never collected
+/// for, filled by userspace from its filter lists. Values are unused.
+#[map(name = "ALLOW_V4")]
+static ALLOW_V4: LpmTrie<[u8; 4], u8> = LpmTrie::with_max_entries(MAX_FILTER_ENTRIES, 0);
+#[map(name = "DENY_V4")]
+static DENY_V4: LpmTrie<[u8; 4], u8> = LpmTrie::with_max_entries(MAX_FILTER_ENTRIES, 0);
+#[map(name = "ALLOW_V6")]
+static ALLOW_V6: LpmTrie<[u8; 16], u8> = LpmTrie::with_max_entries(MAX_FILTER_ENTRIES, 0);
+#[map(name = "DENY_V6")]
+static DENY_V6: LpmTrie<[u8; 16], u8> = LpmTrie::with_max_entries(MAX_FILTER_ENTRIES, 0);
+#[map(name = "ALLOW_PORTS")]
+static ALLOW_PORTS: HashMap<u16, u8> = HashMap::with_max_entries(MAX_FILTER_ENTRIES, 0);
+#[map(name = "DENY_PORTS")]
+static DENY_PORTS: HashMap<u16, u8> = HashMap::with_max_entries(MAX_FILTER_ENTRIES, 0);
+
 #[map(name = "EVENTS")]
-static mut EVENTS: RingBuf = RingBuf::with_byte_size(65536, 0);
+static EVENTS: RingBuf = RingBuf::with_byte_size(65536, 0);
 
-#[repr(C)]
-pub struct RttEvent {
-    pub srtt_us: u32,
-    pub src_addr: u32,
-    pub dst_addr: u32,
-}
+/// Events that could not be reserved in `EVENTS` because userspace fell behind
+#[map(name = "DROPS")]
+static DROPS: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
+
+/// Samples per log-linear bucket, only counted in histogram mode
+#[map(name = "HISTOGRAM")]
+static HISTOGRAM: PerCpuArray<u64> = PerCpuArray::with_max_entries(histogram::BUCKETS, 0);
 
 #[fentry(function = "tcp_rcv_established")]
 pub fn rtt_quantiles(ctx: FEntryContext) -> u32 {
-    let (srtt_us, src_addr, dst_addr) = unsafe {
+    let event = unsafe {
         let sk = ctx.arg::<*const sock>(0);
-        let ts = sk as *const tcp_sock;
+        let protocol = (*sk).sk_protocol;
+        let common = &(*sk).__sk_common;
+        let family = common.skc_family;
+        let ports = &common.__bindgen_anon_3.__bindgen_anon_1;
+        let src_port = ports.skc_num;
+        let dst_port = u16::from_be(ports.skc_dport);
+        let mut src_addr = [0u8; 16];
+        let mut dst_addr = [0u8; 16];
+        match family {
+            AF_INET => {
+                let inner = &common.__bindgen_anon_1.__bindgen_anon_1;
+                src_addr[..4].copy_from_slice(&inner.skc_rcv_saddr.to_ne_bytes());
+                dst_addr[..4].copy_from_slice(&inner.skc_daddr.to_ne_bytes());
+            }
+            AF_INET6 => {
+                src_addr = common.skc_v6_rcv_saddr.in6_u.u6_addr8;
+                dst_addr = common.skc_v6_daddr.in6_u.u6_addr8;
+            }
+            _ => return 0,
+        }
+
+        let config = CONFIG.get(0).copied().unwrap_or_default();
+        if !destination_allowed(config.filter_flags, family, &dst_addr, dst_port)
+            || !sample_due(sk, config.sample_interval_ns)
+        {
+            return 0;
+        }
 
+        let ts = sk as *const tcp_sock;
         let srtt_us = bpf_probe_read_kernel(&(*ts).srtt_us).unwrap_or(0) >> 3;
 
-        let inner = &(*sk).__sk_common.__bindgen_anon_1.__bindgen_anon_1;
-        let src_addr = u32::from_be(inner.skc_rcv_saddr);
-        let dst_addr = u32::from_be(inner.skc_daddr);
+        // read through a volatile pointer so the load isn't folded into the
+        // compile time default
+        if ptr::read_volatile(&MODE) == MODE_HISTOGRAM {
+            if let Some(samples) = HISTOGRAM.get_ptr_mut(histogram::bucket(srtt_us)) {
+                // per-CPU slot, no other program writes it concurrently
+                *samples += 1;
+            }
+            return 0;
+        }
 
-        (srtt_us, src_addr, dst_addr)
-    };
+        // without metrics only the short prefix of the event is sent
+        let (header, flags, metrics) = match config.tcp_metrics {
+            0 => (
+                EventHeader::sized(RttEvent::SHORT_SIZE),
+                0,
+                TcpMetrics::default(),
+            ),
+            _ => (
+                EventHeader::new::<RttEvent>(),
+                EVENT_TCP_METRICS,
+                read_metrics(ts),
+            ),
+        };
 
-    let event = RttEvent {
-        srtt_us,
-        src_addr,
-        dst_addr,
+        RttEvent {
+            header,
+            srtt_us,
+            family,
+            protocol,
+            src_port,
+            dst_port,
+            src_addr,
+            dst_addr,
+            flags,
+            metrics,
+        }
     };
 
-    unsafe {
-        let _ = EVENTS.output(&event, 0);
-        if let Some(mut slot) = EVENTS.reserve((size_of::<RttEvent>() as u64)) {
-            core::ptr::write(
-                slot.as_mut_ptr() as *mut RttEvent,
-                RttEvent {
-                    srtt_us,
-                    src_addr,
-                    dst_addr,
-                },
-            );
+    let reserved = match event.flags & EVENT_TCP_METRICS {
+        0 => EVENTS
+            .reserve::<[u8; RttEvent::SHORT_SIZE]>(0)
+            .map(|mut slot| {
+                slot.write(*event.short());
+                slot.submit(0);
+            }),
+        _ => EVENTS.reserve::<RttEvent>(0).map(|mut slot| {
+            slot.write(event);
             slot.submit(0);
+        }),
+    };
+    match reserved {
+        Some(()) => {}
+        None => {
+            if let Some(drops) = DROPS.get_ptr_mut(0) {
+                // per-CPU slot, no other program writes it concurrently
+                unsafe { *drops += 1 };
+            }
         }
     }
     0
 }
 
+/// Reads the `tcp_sock` fields besides `srtt_us` the collector keeps digests of
+unsafe fn read_metrics(ts: *const tcp_sock) -> TcpMetrics {
+    TcpMetrics {
+        // stored scaled by 4, like srtt_us by 8
+        mdev_us: bpf_probe_read_kernel(&(*ts).mdev_us).unwrap_or(0) >> 2,
+        rtt_min_us: bpf_probe_read_kernel(&(*ts).rtt_min.s[0].v).unwrap_or(u32::MAX),
+        snd_cwnd: bpf_probe_read_kernel(&(*ts).snd_cwnd).unwrap_or(0),
+        total_retrans: bpf_probe_read_kernel(&(*ts).total_retrans).unwrap_or(0),
+        packets_out: bpf_probe_read_kernel(&(*ts).packets_out).unwrap_or(0),
+        mss_cache: bpf_probe_read_kernel(&(*ts).mss_cache).unwrap_or(0),
+        rate_delivered: bpf_probe_read_kernel(&(*ts).rate_delivered).unwrap_or(0),
+        rate_interval_us: bpf_probe_read_kernel(&(*ts).rate_interval_us).unwrap_or(0),
+    }
+}
+
+/// Whether the filter maps let samples for the destination through. Deny
+/// lists take precedence. IPv4-mapped IPv6 destinations are matched against
+/// the IPv4 lists.
+fn destination_allowed(flags: u64, family: u16, addr: &[u8; 16], port: u16) -> bool {
+    let mapped = family == AF_INET6
+        && addr[..10].iter().all(|&b| b == 0)
+        && addr[10] == 0xff
+        && addr[11] == 0xff;
+    let (denied, allowed) = if family == AF_INET || mapped {
+        let v4 = match mapped {
+            true => [addr[12], addr[13], addr[14], addr[15]],
+            false => [addr[0], addr[1], addr[2], addr[3]],
+        };
+        let key = Key::new(32, v4);
+        (DENY_V4.get(&key).is_some(), ALLOW_V4.get(&key).is_some())
+    } else {
+        let key = Key::new(128, *addr);
+        (DENY_V6.get(&key).is_some(), ALLOW_V6.get(&key).is_some())
+    };
+
+    if denied || unsafe { DENY_PORTS.get(&port) }.is_some() {
+        return false;
+    }
+
+    (flags & FILTER_ALLOW_DESTINATIONS == 0 || allowed)
+        && (flags & FILTER_ALLOW_PORTS == 0 || unsafe { ALLOW_PORTS.get(&port) }.is_some())
+}
+
+/// Whether the connection's last sample is at least `interval` ns old,
+/// recording now as its last sample if so. A socket reused for a new
+/// connection inherits the old connection's last sample, delaying its first
+/// sample by at most one interval.
+fn sample_due(sk: *const sock, interval: u64) -> bool {
+    if interval == 0 {
+        return true;
+    }
+
+    let key = sk as u64;
+    let now = unsafe { bpf_ktime_get_ns() };
+    let last = LAST_SAMPLE.get_ptr_mut(&key);
+    if !sampling::due(last.map(|last| unsafe { *last }), now, interval) {
+        return false;
+    }
+    match last {
+        // the socket lock serialises tcp_rcv_established per connection
+        Some(last) => unsafe { *last = now },
+        None => {
+            let _ = LAST_SAMPLE.insert(&key, &now, 0);
+        }
+    }
+    true
+}
+
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
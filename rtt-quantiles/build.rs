@@ -2,7 +2,9 @@ use anyhow::{anyhow, Context as _};
 use aya_build::cargo_metadata;
 
 fn main() -> anyhow::Result<()> {
+    // rtt-quantiles-ebpf is a workspace of its own, see the workspace manifest
     let cargo_metadata::Metadata { packages, .. } = cargo_metadata::MetadataCommand::new()
+        .manifest_path("../rtt-quantiles-ebpf/Cargo.toml")
         .no_deps()
         .exec()
         .context("MetadataCommand::exec")?;
@@ -10,5 +12,7 @@ fn main() -> anyhow::Result<()> {
         .into_iter()
         .find(|cargo_metadata::Package { name, .. }| name == "rtt-quantiles-ebpf")
         .ok_or_else(|| anyhow!("rtt-quantiles-ebpf package not found"))?;
+    // aya_build runs cargo in the current directory
+    std::env::set_current_dir("../rtt-quantiles-ebpf").context("chdir to rtt-quantiles-ebpf")?;
     aya_build::build_ebpf([ebpf_package])
 }
@@ -0,0 +1,496 @@
+use std::{
+    fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context as _};
+use chrono::TimeDelta;
+use clap::{ArgAction, Parser, ValueEnum};
+use ipnet::IpNet;
+use rtt_tdigest::StoreConfig;
+use serde::Deserialize;
+
+use crate::filters::Filters;
+
+const DEFAULT_APP: &str = "sample-app";
+const DEFAULT_STORE: &str = "dynamodb";
+const DEFAULT_WINDOW: &str = "1m";
+const DEFAULT_COMPRESSION: usize = 100;
+const DEFAULT_LOG_EVERY: u64 = 1000;
+const DEFAULT_MAX_BATCH: NonZeroUsize = NonZeroUsize::new(1024).unwrap();
+const DEFAULT_MAX_DESTINATIONS: NonZeroUsize = NonZeroUsize::new(1024).unwrap();
+const DEFAULT_SPOOL_DIR: &str = "/var/lib/rtt-quantiles/spool";
+const DEFAULT_SAMPLE_INTERVAL_MS: u64 = 0;
+const DEFAULT_SPOOL_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Collects TCP round trip times with eBPF and stores them as t-digests.
+///
+/// Options are read from the command line, then the environment, then the
+/// config file given with `--config`.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// TOML config file
+    #[arg(short, long, env = "RTT_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// How samples are collected [default: events]
+    #[arg(long, env = "RTT_MODE", value_enum)]
+    mode: Option<Mode>,
+
+    /// App the digests are stored for [default: sample-app]
+    #[arg(long, env = "RTT_APP")]
+    app: Option<String>,
+
+    /// Node id the digests are stored for [default: hostname]
+    #[arg(long, env = "RTT_NODE_ID")]
+    node_id: Option<String>,
+
+    /// Storage backend, `dynamodb` or `sqlite` [default: dynamodb]
+    #[arg(long, env = "RTT_STORE")]
+    store: Option<String>,
+
+    /// SQLite database path [default: rtt-tdigests.db]
+    #[arg(long, env = "RTT_SQLITE_PATH")]
+    sqlite_path: Option<PathBuf>,
+
+    /// DynamoDB region [default: from the AWS environment, else us-east-1]
+    #[arg(long, env = "RTT_REGION")]
+    region: Option<String>,
+
+    /// Length of the window each digest covers, whole minutes or hours, e.g.
+    /// `1m`, `5m`, `1h` [default: 1m]
+    #[arg(long, env = "RTT_WINDOW")]
+    window: Option<String>,
+
+    /// Maximum number of centroids kept per digest [default: 100]
+    #[arg(long, env = "RTT_COMPRESSION")]
+    compression: Option<usize>,
+
+    /// Number of destinations digests are kept for [default: 1024]
+    #[arg(long, env = "RTT_MAX_DESTINATIONS")]
+    max_destinations: Option<NonZeroUsize>,
+
+    /// Key destination digests by address only instead of address and port,
+    /// `--no-ports=false` overrides the config file [default: false]
+    #[arg(
+        long,
+        env = "RTT_NO_PORTS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        action = ArgAction::Set
+    )]
+    no_ports: Option<bool>,
+
+    /// Take at most one sample per connection every this many milliseconds, 0
+    /// takes every sample [default: 0]
+    #[arg(long, env = "RTT_SAMPLE_INTERVAL_MS")]
+    sample_interval_ms: Option<u64>,
+
+    /// Also read mdev, rtt_min, snd_cwnd, total_retrans, packets_out and the
+    /// delivery rate and store a host-wide digest of each [default: false]
+    #[arg(
+        long,
+        env = "RTT_TCP_METRICS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        action = ArgAction::Set
+    )]
+    tcp_metrics: Option<bool>,
+
+    /// Log progress every this many samples [default: 1000]
+    #[arg(long, env = "RTT_LOG_EVERY")]
+    log_every: Option<u64>,
+
+    /// Most ring buffer records read per wakeup before yielding to other
+    /// tasks [default: 1024]
+    #[arg(long, env = "RTT_MAX_BATCH")]
+    max_batch: Option<NonZeroUsize>,
+
+    /// Directory digests are spooled to while the store is unavailable
+    /// [default: /var/lib/rtt-quantiles/spool]
+    #[arg(long, env = "RTT_SPOOL_DIR")]
+    spool_dir: Option<PathBuf>,
+
+    /// Size cap of the spool directory in bytes [default: 67108864]
+    #[arg(long, env = "RTT_SPOOL_MAX_BYTES")]
+    spool_max_bytes: Option<u64>,
+
+    /// Only collect destinations in these networks, e.g. `10.0.0.0/8`
+    #[arg(long, env = "RTT_ALLOW_DESTINATIONS", value_delimiter = ',')]
+    allow_destinations: Vec<IpNet>,
+
+    /// Never collect destinations in these networks
+    #[arg(long, env = "RTT_DENY_DESTINATIONS", value_delimiter = ',')]
+    deny_destinations: Vec<IpNet>,
+
+    /// Only collect these destination ports
+    #[arg(long, env = "RTT_ALLOW_PORTS", value_delimiter = ',')]
+    allow_ports: Vec<u16>,
+
+    /// Never collect these destination ports
+    #[arg(long, env = "RTT_DENY_PORTS", value_delimiter = ',')]
+    deny_ports: Vec<u16>,
+}
+
+/// Layout of the config file, every setting is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    mode: Option<Mode>,
+    app: Option<String>,
+    node_id: Option<String>,
+    window: Option<String>,
+    compression: Option<usize>,
+    max_destinations: Option<NonZeroUsize>,
+    by_port: Option<bool>,
+    sample_interval_ms: Option<u64>,
+    tcp_metrics: Option<bool>,
+    log_every: Option<u64>,
+    max_batch: Option<NonZeroUsize>,
+    store: FileStore,
+    spool: FileSpool,
+    filters: Filters,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileStore {
+    backend: Option<String>,
+    sqlite_path: Option<PathBuf>,
+    region: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileSpool {
+    dir: Option<PathBuf>,
+    max_bytes: Option<u64>,
+}
+
+/// How samples get from the kernel to the digests
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Every sample is sent to userspace, digests are kept per destination
+    #[default]
+    Events,
+    /// Samples are counted into a log-linear histogram in the kernel and read
+    /// periodically, only the host-wide digest is kept
+    Histogram,
+}
+
+/// Collector settings after merging the command line, environment and config file
+#[derive(Debug)]
+pub struct Config {
+    pub mode: Mode,
+    pub app: String,
+    pub node_id: String,
+    pub store: StoreConfig,
+    pub window: TimeDelta,
+    /// Aggregation level digests are stored under, the window as given, e.g. `5m`
+    pub agg_level: String,
+    pub compression: usize,
+    pub max_destinations: NonZeroUsize,
+    pub by_port: bool,
+    /// Minimum time between two samples of the same connection, zero keeps
+    /// every sample
+    pub sample_interval: Duration,
+    /// Whether digests of the extra `tcp_sock` metrics are kept
+    pub tcp_metrics: bool,
+    pub log_every: u64,
+    pub max_batch: NonZeroUsize,
+    pub spool_dir: PathBuf,
+    pub spool_max_bytes: u64,
+    pub filters: Filters,
+}
+
+impl Config {
+    /// Parse the command line and load the config file it points to
+    pub fn load() -> anyhow::Result<Self> {
+        let cli = Cli::parse();
+        let file = match &cli.config {
+            Some(path) => read_file(path)?,
+            None => FileConfig::default(),
+        };
+
+        Self::merge(cli, file)
+    }
+
+    fn merge(cli: Cli, file: FileConfig) -> anyhow::Result<Self> {
+        let node_id = match cli.node_id.or(file.node_id) {
+            Some(node_id) => node_id,
+            None => hostname()?,
+        };
+        let store = StoreConfig::parse(
+            cli.store
+                .or(file.store.backend)
+                .as_deref()
+                .unwrap_or(DEFAULT_STORE),
+            cli.sqlite_path.or(file.store.sqlite_path),
+            cli.region.or(file.store.region),
+        )?;
+        let agg_level = cli
+            .window
+            .or(file.window)
+            .unwrap_or_else(|| DEFAULT_WINDOW.to_string());
+        let window = parse_window(&agg_level)?;
+
+        let compression = cli
+            .compression
+            .or(file.compression)
+            .unwrap_or(DEFAULT_COMPRESSION);
+        if compression == 0 {
+            return Err(anyhow!("compression must be at least 1"));
+        }
+
+        let mut filters = file.filters;
+        override_list(&mut filters.allow_destinations, cli.allow_destinations);
+        override_list(&mut filters.deny_destinations, cli.deny_destinations);
+        override_list(&mut filters.allow_ports, cli.allow_ports);
+        override_list(&mut filters.deny_ports, cli.deny_ports);
+
+        Ok(Config {
+            mode: cli.mode.or(file.mode).unwrap_or_default(),
+            app: cli
+                .app
+                .or(file.app)
+                .unwrap_or_else(|| DEFAULT_APP.to_string()),
+            node_id,
+            store,
+            window,
+            agg_level,
+            compression,
+            max_destinations: cli
+                .max_destinations
+                .or(file.max_destinations)
+                .unwrap_or(DEFAULT_MAX_DESTINATIONS),
+            by_port: match cli.no_ports {
+                Some(no_ports) => !no_ports,
+                None => file.by_port.unwrap_or(true),
+            },
+            sample_interval: Duration::from_millis(
+                cli.sample_interval_ms
+                    .or(file.sample_interval_ms)
+                    .unwrap_or(DEFAULT_SAMPLE_INTERVAL_MS),
+            ),
+            tcp_metrics: cli.tcp_metrics.or(file.tcp_metrics).unwrap_or(false),
+            log_every: cli
+                .log_every
+                .or(file.log_every)
+                .unwrap_or(DEFAULT_LOG_EVERY)
+                .max(1),
+            max_batch: cli
+                .max_batch
+                .or(file.max_batch)
+                .unwrap_or(DEFAULT_MAX_BATCH),
+            spool_dir: cli
+                .spool_dir
+                .or(file.spool.dir)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_SPOOL_DIR)),
+            spool_max_bytes: cli
+                .spool_max_bytes
+                .or(file.spool.max_bytes)
+                .unwrap_or(DEFAULT_SPOOL_MAX_BYTES),
+            filters,
+        })
+    }
+}
+
+/// Lists given on the command line or in the environment replace the file's
+fn override_list<T>(list: &mut Vec<T>, from_cli: Vec<T>) {
+    if !from_cli.is_empty() {
+        *list = from_cli;
+    }
+}
+
+fn read_file(path: &Path) -> anyhow::Result<FileConfig> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+
+    toml::from_str(&contents)
+        .with_context(|| format!("failed to parse config file {}", path.display()))
+}
+
+/// Parse a window length such as `1m`, `5m` or `1h`. Stored windows start on
+/// whole minutes so the length must be whole minutes too.
+fn parse_window(window: &str) -> anyhow::Result<TimeDelta> {
+    let invalid = || anyhow!("invalid window {:?}, expected e.g. 1m, 5m or 1h", window);
+
+    let split = window
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (count, unit) = window.split_at(split);
+    let count = count.parse::<i64>().map_err(|_| invalid())?;
+
+    let delta = match unit {
+        "m" => TimeDelta::try_minutes(count),
+        "h" => TimeDelta::try_hours(count),
+        _ => None,
+    };
+
+    match delta {
+        Some(delta) if delta > TimeDelta::zero() => Ok(delta),
+        _ => Err(invalid()),
+    }
+}
+
+fn hostname() -> anyhow::Result<String> {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+        .context("failed to read hostname, set a node id instead")?;
+
+    Ok(hostname.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::{Mutex, PoisonError};
+
+    /// Held while the command line is parsed, clap reads the process
+    /// environment and tests run in parallel
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Parse the command line with only the `env` variables set, any `RTT_*`
+    /// variables of the developer's shell are cleared first
+    fn parse(env: &[(&str, &str)], args: &[&str]) -> Result<Cli, clap::Error> {
+        let _env = ENV.lock().unwrap_or_else(PoisonError::into_inner);
+        for (key, _) in env::vars() {
+            if key.starts_with("RTT_") {
+                env::remove_var(key);
+            }
+        }
+        for (key, value) in env {
+            env::set_var(key, value);
+        }
+
+        let cli = Cli::try_parse_from(["rtt-quantiles"].iter().chain(args));
+        for (key, _) in env {
+            env::remove_var(key);
+        }
+        cli
+    }
+
+    fn merged_with_env(env: &[(&str, &str)], args: &[&str], file: &str) -> Config {
+        let args: Vec<_> = ["--node-id", "test-node"]
+            .iter()
+            .chain(args)
+            .copied()
+            .collect();
+        let cli = parse(env, &args).unwrap();
+        let file = toml::from_str(file).unwrap();
+
+        Config::merge(cli, file).unwrap()
+    }
+
+    fn merged(args: &[&str], file: &str) -> Config {
+        merged_with_env(&[], args, file)
+    }
+
+    #[test]
+    fn parse_window_accepts_whole_minutes_and_hours() {
+        assert_eq!(parse_window("1m").unwrap(), TimeDelta::minutes(1));
+        assert_eq!(parse_window("5m").unwrap(), TimeDelta::minutes(5));
+        assert_eq!(parse_window("2h").unwrap(), TimeDelta::hours(2));
+    }
+
+    #[test]
+    fn parse_window_rejects_other_lengths() {
+        for window in ["", "m", "1", "0m", "-1m", "1.5m", "30s", "1d", "1 m"] {
+            assert!(parse_window(window).is_err(), "{window:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn defaults_apply_without_options() {
+        let config = merged(&[], "");
+
+        assert_eq!(config.mode, Mode::Events);
+        assert_eq!(config.app, DEFAULT_APP);
+        assert_eq!(config.agg_level, DEFAULT_WINDOW);
+        assert!(config.by_port);
+        assert!(!config.tcp_metrics);
+        assert!(config.spool_dir.is_absolute());
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let file = r#"
+            app = "from-file"
+            window = "5m"
+            mode = "histogram"
+
+            [spool]
+            dir = "/file/spool"
+        "#;
+
+        let config = merged(&[], file);
+        assert_eq!(config.app, "from-file");
+        assert_eq!(config.window, TimeDelta::minutes(5));
+        assert_eq!(config.mode, Mode::Histogram);
+        assert_eq!(config.spool_dir, PathBuf::from("/file/spool"));
+
+        let config = merged(
+            &["--app", "from-cli", "--window", "1h", "--mode", "events"],
+            file,
+        );
+        assert_eq!(config.app, "from-cli");
+        assert_eq!(config.agg_level, "1h");
+        assert_eq!(config.mode, Mode::Events);
+        assert_eq!(config.spool_dir, PathBuf::from("/file/spool"));
+    }
+
+    #[test]
+    fn environment_sits_between_command_line_and_file() {
+        let env = [("RTT_COMPRESSION", "200")];
+        let from_env = merged_with_env(&env, &[], "compression = 300");
+        let from_cli = merged_with_env(&env, &["--compression", "100"], "compression = 300");
+
+        assert_eq!(from_env.compression, 200);
+        assert_eq!(from_cli.compression, 100);
+        assert_eq!(merged(&[], "compression = 300").compression, 300);
+    }
+
+    #[test]
+    fn boolean_flags_override_the_file_either_way() {
+        let file = "tcp_metrics = true\nby_port = false";
+
+        let config = merged(&[], file);
+        assert!(config.tcp_metrics);
+        assert!(!config.by_port);
+
+        let config = merged(&["--tcp-metrics=false", "--no-ports=false"], file);
+        assert!(!config.tcp_metrics);
+        assert!(config.by_port);
+
+        let config = merged(&["--tcp-metrics", "--no-ports"], "");
+        assert!(config.tcp_metrics);
+        assert!(!config.by_port);
+    }
+
+    #[test]
+    fn filter_lists_from_the_command_line_replace_the_file() {
+        let file = r#"
+            [filters]
+            allow_ports = [443]
+            deny_ports = [22]
+        "#;
+
+        let config = merged(&["--allow-ports", "80,8080"], file);
+        assert_eq!(config.filters.allow_ports, [80, 8080]);
+        assert_eq!(config.filters.deny_ports, [22]);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let cli = parse(&[], &["--node-id", "n", "--compression", "0"]).unwrap();
+        assert!(Config::merge(cli, FileConfig::default()).is_err());
+
+        assert!(toml::from_str::<FileConfig>("unknown = 1").is_err());
+    }
+}
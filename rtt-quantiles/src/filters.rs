@@ -0,0 +1,154 @@
+use anyhow::anyhow;
+use aya::{
+    maps::{
+        lpm_trie::{Key, LpmTrie},
+        HashMap, Map,
+    },
+    Ebpf,
+};
+use ipnet::IpNet;
+use rtt_quantiles_common::{FILTER_ALLOW_DESTINATIONS, FILTER_ALLOW_PORTS, MAX_FILTER_ENTRIES};
+use serde::Deserialize;
+
+/// Destinations samples are collected for, checked by the eBPF program before
+/// a sample is taken. Deny lists take precedence, empty allow lists allow
+/// everything.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filters {
+    pub allow_destinations: Vec<IpNet>,
+    pub deny_destinations: Vec<IpNet>,
+    pub allow_ports: Vec<u16>,
+    pub deny_ports: Vec<u16>,
+}
+
+impl Filters {
+    /// Fill the program's filter maps, returning the `KernelConfig` filter
+    /// flags for the allow lists in use
+    pub fn load(&self, ebpf: &mut Ebpf) -> anyhow::Result<u64> {
+        load_networks(ebpf, "ALLOW_V4", "ALLOW_V6", &self.allow_destinations)?;
+        load_networks(ebpf, "DENY_V4", "DENY_V6", &self.deny_destinations)?;
+        load_ports(ebpf, "ALLOW_PORTS", &self.allow_ports)?;
+        load_ports(ebpf, "DENY_PORTS", &self.deny_ports)?;
+
+        Ok(self.flags())
+    }
+
+    /// `KernelConfig` filter flags for the allow lists in use
+    fn flags(&self) -> u64 {
+        let mut flags = 0;
+        if !self.allow_destinations.is_empty() {
+            flags |= FILTER_ALLOW_DESTINATIONS;
+        }
+        if !self.allow_ports.is_empty() {
+            flags |= FILTER_ALLOW_PORTS;
+        }
+        flags
+    }
+}
+
+fn load_networks(ebpf: &mut Ebpf, v4: &str, v6: &str, nets: &[IpNet]) -> anyhow::Result<()> {
+    check_len(v4, nets.len())?;
+
+    // the tries match on the network address, host bits are dropped
+    for net in nets.iter().map(IpNet::trunc) {
+        match net {
+            IpNet::V4(net) => {
+                let key = Key::new(u32::from(net.prefix_len()), net.addr().octets());
+                LpmTrie::<_, [u8; 4], u8>::try_from(map_mut(ebpf, v4)?)?.insert(&key, 1, 0)?;
+            }
+            IpNet::V6(net) => {
+                let key = Key::new(u32::from(net.prefix_len()), net.addr().octets());
+                LpmTrie::<_, [u8; 16], u8>::try_from(map_mut(ebpf, v6)?)?.insert(&key, 1, 0)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn load_ports(ebpf: &mut Ebpf, name: &str, ports: &[u16]) -> anyhow::Result<()> {
+    check_len(name, ports.len())?;
+
+    let mut map = HashMap::<_, u16, u8>::try_from(map_mut(ebpf, name)?)?;
+    for port in ports {
+        map.insert(port, 1, 0)?;
+    }
+
+    Ok(())
+}
+
+fn check_len(name: &str, len: usize) -> anyhow::Result<()> {
+    if len > MAX_FILTER_ENTRIES as usize {
+        return Err(anyhow!(
+            "{} filter entries given for {}, at most {} are supported",
+            len,
+            name,
+            MAX_FILTER_ENTRIES
+        ));
+    }
+
+    Ok(())
+}
+
+fn map_mut<'a>(ebpf: &'a mut Ebpf, name: &str) -> anyhow::Result<&'a mut Map> {
+    ebpf.map_mut(name)
+        .ok_or_else(|| anyhow!("{} map not found", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_follow_the_allow_lists_in_use() {
+        let net = |s: &str| s.parse::<IpNet>().unwrap();
+
+        assert_eq!(Filters::default().flags(), 0);
+
+        let deny_only = Filters {
+            deny_destinations: vec![net("10.0.0.0/8")],
+            deny_ports: vec![22],
+            ..Filters::default()
+        };
+        assert_eq!(deny_only.flags(), 0);
+
+        let allow_destinations = Filters {
+            allow_destinations: vec![net("2001:db8::/32")],
+            ..Filters::default()
+        };
+        assert_eq!(allow_destinations.flags(), FILTER_ALLOW_DESTINATIONS);
+
+        let allow_both = Filters {
+            allow_destinations: vec![net("10.0.0.0/8")],
+            allow_ports: vec![443],
+            ..Filters::default()
+        };
+        assert_eq!(
+            allow_both.flags(),
+            FILTER_ALLOW_DESTINATIONS | FILTER_ALLOW_PORTS
+        );
+    }
+
+    #[test]
+    fn lists_are_capped_at_the_map_size() {
+        assert!(check_len("ALLOW_PORTS", MAX_FILTER_ENTRIES as usize).is_ok());
+        assert!(check_len("ALLOW_PORTS", MAX_FILTER_ENTRIES as usize + 1).is_err());
+    }
+
+    #[test]
+    fn filters_parse_from_the_config_file() {
+        let filters: Filters = toml::from_str(
+            r#"
+            allow_destinations = ["10.0.0.0/8", "2001:db8::/32"]
+            deny_ports = [22]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(filters.allow_destinations.len(), 2);
+        assert_eq!(filters.deny_ports, [22]);
+        assert!(toml::from_str::<Filters>("allow_destinations = [\"10.0.0.300/8\"]").is_err());
+        assert!(toml::from_str::<Filters>("allow = []").is_err());
+    }
+}
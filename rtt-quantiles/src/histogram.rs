@@ -0,0 +1,38 @@
+use aya::maps::{MapData, PerCpuArray};
+use rtt_quantiles_common::histogram::{bounds, BUCKETS};
+
+/// Reader of the eBPF program's `HISTOGRAM` map, which counts samples per
+/// log-linear bucket on every CPU.
+///
+/// The map is never cleared, zeroing a bucket from userspace would lose the
+/// samples counted between reading and zeroing it. Instead the counts seen on
+/// the previous read are kept and each read returns the difference.
+pub struct KernelHistogram {
+    map: PerCpuArray<MapData, u64>,
+    seen: Vec<u64>,
+}
+
+impl KernelHistogram {
+    pub fn new(map: PerCpuArray<MapData, u64>) -> Self {
+        Self {
+            map,
+            seen: vec![0; BUCKETS as usize],
+        }
+    }
+
+    /// Samples counted since the previous read, as pairs of a bucket's
+    /// midpoint in microseconds and its sample count, in ascending order
+    pub fn read(&mut self) -> anyhow::Result<Vec<(u32, u64)>> {
+        let mut buckets = Vec::new();
+        for (bucket, seen) in (0..BUCKETS).zip(self.seen.iter_mut()) {
+            let total: u64 = self.map.get(&bucket, 0)?.iter().sum();
+            let samples = total.wrapping_sub(std::mem::replace(seen, total));
+            if samples > 0 {
+                let (low, high) = bounds(bucket);
+                buckets.push((low + (high - low) / 2, samples));
+            }
+        }
+
+        Ok(buckets)
+    }
+}
@@ -1,34 +1,70 @@
+mod config;
+mod filters;
+mod histogram;
+mod spool;
+mod summaries;
+
 use anyhow::Context as _;
 use aya::{programs::FEntry, Btf};
 #[rustfmt::skip]
 use log::{debug, warn};
 use std::{
-    collections::VecDeque,
-    net::Ipv4Addr,
+    ops::Deref,
     ptr,
     time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
-use aws_config;
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_dynamodb::Client;
-use aya::maps::RingBuf;
-use rtt_tdigest::{Service, Summary};
-use std::sync::{Arc, Mutex};
-use tokio::{signal, time};
-
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct RttEvent {
-    pub srtt_us: u32,
-    pub src_addr: u32,
-    pub dst_addr: u32,
-}
+use aya::maps::{Array, MapData, PerCpuArray, RingBuf};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use config::{Config, Mode};
+use histogram::KernelHistogram;
+use rtt_quantiles_common::{KernelConfig, RttEvent, EVENT_VERSION, MODE_EVENTS, MODE_HISTOGRAM};
+use rtt_tdigest::{Service, TDigestRecord};
+use spool::Spool;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use summaries::Summaries;
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    signal::{
+        self,
+        unix::{self, SignalKind},
+    },
+    sync::watch,
+    time,
+};
+
+/// Attempts made to store a digest while the store reports transient failures
+const STORE_ATTEMPTS: u32 = 4;
+
+/// Delay before the first store retry, doubled after every attempt
+const STORE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How often the spool is checked for records to send while the store is healthy
+const DRAIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Upper bound on the delay between drain attempts while the store keeps failing
+const DRAIN_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Time allowed between SIGTERM/SIGINT and exiting
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Time a window may spend on store attempts before the rest of it is spooled,
+/// bounds how long the next window's swap can be held up by a slow store
+const STORE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time from a shutdown request until whatever is left to store, of the
+/// window being stored and of the partial window, is spooled instead. Leaves
+/// room within `SHUTDOWN_DEADLINE` for spooling.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// How often the in-kernel histogram is read in histogram mode
+const HISTOGRAM_INTERVAL: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
+    let config = Config::load()?;
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -45,101 +81,765 @@ async fn main() -> anyhow::Result<()> {
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
     // reach for `Bpf::load_file` instead.
-    let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/rtt-quantiles"
-    )))?;
+    let mode = match config.mode {
+        Mode::Events => MODE_EVENTS,
+        Mode::Histogram => MODE_HISTOGRAM,
+    };
+    let mut ebpf = aya::EbpfLoader::new()
+        .set_global("MODE", &mode, true)
+        .load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/rtt-quantiles"
+        )))?;
     if let Err(e) = aya_log::EbpfLogger::init(&mut ebpf) {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {e}");
     }
+    let kernel_config = KernelConfig {
+        sample_interval_ns: config.sample_interval.as_nanos().try_into()?,
+        filter_flags: config.filters.load(&mut ebpf)?,
+        tcp_metrics: config.tcp_metrics.into(),
+    };
+    Array::try_from(
+        ebpf.map_mut("CONFIG")
+            .ok_or(anyhow!("CONFIG map not found"))?,
+    )?
+    .set(0, kernel_config, 0)?;
+
     let btf = Btf::from_sys_fs().context("BTF from sysfs")?;
     let program: &mut FEntry = ebpf.program_mut("rtt_quantiles").unwrap().try_into()?;
     program.load("tcp_rcv_established", &btf)?;
-    program.attach()?;
+    let link_id = program.attach()?;
 
-    let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
-    let config = aws_config::from_env().region(region_provider).load().await;
-    let client = Client::new(&config);
-    let svc = Service::new(client, "sample-app".to_string(), "local".to_string());
+    let store = config.store.open().await?;
+    let svc = Service::new(store, config.app.clone(), config.node_id.clone());
+    let spool = Arc::new(Mutex::new(Spool::open(
+        &config.spool_dir,
+        config.spool_max_bytes,
+    )?));
+    tokio::spawn(drain_spool(svc.clone(), Arc::clone(&spool)));
 
     let events_map = ebpf
-        .map_mut("EVENTS")
+        .take_map("EVENTS")
         .ok_or(anyhow!("EVENTS map not found"))?;
-    let mut ringbuf = RingBuf::try_from(events_map)?;
-    let start = Instant::now();
-    let summary_mutex = Arc::new(Mutex::new(Summary::new()));
-
-    tokio::spawn({
-        let initial_tick = time::Instant::now() + Duration::from_secs(60);
-        let mut store_interval = time::interval_at(initial_tick, Duration::from_secs(61));
-        let summary_mutex = Arc::clone(&summary_mutex);
-
-        async move {
-            loop {
-                store_interval.tick().await;
-                println!("Attempting to store T Digest");
-                let digest = match summary_mutex.lock() {
-                    Ok(summary) => summary.digest(),
-                    Err(e) => {
-                        warn!("Failed to lock summary mutex: {}", e);
-                        continue;
-                    }
-                };
-
-                match svc.store_tdigest("1m".to_string(), digest).await {
-                    Ok(_) => println!("Stored 1m T-Digest successfully"),
-                    Err(e) => warn!("Failed to store t digest: {}", e),
-                }
+    let mut ringbuf = AsyncFd::with_interest(RingBuf::try_from(events_map)?, Interest::READABLE)?;
+    let drops_map = ebpf
+        .take_map("DROPS")
+        .ok_or(anyhow!("DROPS map not found"))?;
+    let drops = Arc::new(PerCpuArray::<_, u64>::try_from(drops_map)?);
+    let mut histogram = match config.mode {
+        Mode::Events => None,
+        Mode::Histogram => {
+            let histogram_map = ebpf
+                .take_map("HISTOGRAM")
+                .ok_or(anyhow!("HISTOGRAM map not found"))?;
+            if config.tcp_metrics {
+                warn!("TCP metrics are only collected in events mode");
             }
+            Some(KernelHistogram::new(PerCpuArray::try_from(histogram_map)?))
         }
-    });
+    };
+    let mut histogram_tick = time::interval(HISTOGRAM_INTERVAL);
+    let start = Instant::now();
+    let mut processed: u64 = 0;
+    let summary_mutex = Arc::new(Mutex::new(Summaries::new(
+        config.max_destinations,
+        config.by_port,
+        config.compression,
+    )));
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(None);
+    let store_task = tokio::spawn(store_windows(
+        svc.clone(),
+        Arc::clone(&spool),
+        Arc::clone(&summary_mutex),
+        Arc::clone(&drops),
+        config.window,
+        config.agg_level.clone(),
+        shutdown_rx,
+    ));
+    let mut sigterm = unix::signal(SignalKind::terminate())?;
 
     loop {
         tokio::select! {
             _ = signal::ctrl_c() => {
-                println!("Exiting...");
+                println!("Received SIGINT, exiting...");
                 break;
             }
-            _ = tokio::task::yield_now() => {
-                if let Some(data) = ringbuf.next() {
-                    let event = unsafe { ptr::read(data.as_ptr() as *const RttEvent) };
-                    let mut rtt_summary = match summary_mutex.lock() {
-                        Ok(summary) => summary,
-                        Err(e) => {
-                            warn!("Failed to lock summary mutex: {}", e);
-                            continue;
+            _ = sigterm.recv() => {
+                println!("Received SIGTERM, exiting...");
+                break;
+            }
+            guard = ringbuf.readable_mut() => {
+                let mut guard = guard?;
+                // drain up to a batch of records per wakeup, locking once for all of them
+                let batch = read_batch(
+                    guard.get_inner_mut(),
+                    &mut lock_summaries(&summary_mutex),
+                    config.max_batch.get(),
+                    |event, summaries| {
+                        processed += 1;
+
+                        if processed.is_multiple_of(config.log_every) {
+                            let rtt_summary = summaries.total_mut();
+                            log_rate(processed, start, &drops);
+                            println!(
+                                "RTT={}µs proto={} src={} dst={}, p99:{:.1}ms, p90:{:.1}ms",
+                                event.srtt_us,
+                                event.protocol,
+                                event.src(),
+                                event.dst(),
+                                rtt_summary.p99(),
+                                rtt_summary.p90(),
+                            );
                         }
-                    };
-
-                    rtt_summary.add_rtt(event.srtt_us);
-
-                    if rtt_summary.count() % 1000 == 0 {
-                        let elapsed = start.elapsed().as_secs_f64();
-                        let rate = rtt_summary.count() as f64 / elapsed;
-                        println!(
-                            "📊 {} samples in {:.1}s = {:.1} events/sec",
-                            rtt_summary.count(),
-                            elapsed,
-                            rate
-                        );
-                        println!(
-                            "RTT={}µs src={} dst={}, p99:{:.1}ms, p90:{:.1}ms",
-                            event.srtt_us,
-                            u32_to_ip(event.src_addr),
-                            u32_to_ip(event.dst_addr),
-                            rtt_summary.p99(),
-                            rtt_summary.p90(),
-                        );
+                    },
+                );
+
+                if batch < config.max_batch.get() {
+                    // the ring buffer is empty, wait for the kernel to signal new records
+                    guard.clear_ready();
+                } else {
+                    // records may be left, let other tasks run before reading on
+                    tokio::task::yield_now().await;
+                }
+            }
+            _ = histogram_tick.tick(), if histogram.is_some() => {
+                if let Some(histogram) = histogram.as_mut() {
+                    let before = processed;
+                    match read_histogram(histogram, &summary_mutex) {
+                        Ok(samples) => processed += samples,
+                        Err(e) => warn!("Failed to read histogram: {}", e),
+                    }
+
+                    if before / config.log_every != processed / config.log_every {
+                        log_rate(processed, start, &drops);
+                        let mut summaries = lock_summaries(&summary_mutex);
+                        let rtt_summary = summaries.total_mut();
+                        println!("p99:{:.1}ms, p90:{:.1}ms", rtt_summary.p99(), rtt_summary.p90());
                     }
                 }
             }
         }
     }
 
+    // stop sampling first so the partial window is final once it is stored
+    let program: &mut FEntry = ebpf.program_mut("rtt_quantiles").unwrap().try_into()?;
+    program.detach(link_id)?;
+    // records written before the detach are still queued
+    read_batch(
+        ringbuf.get_mut(),
+        &mut lock_summaries(&summary_mutex),
+        usize::MAX,
+        |_, _| {},
+    );
+    drop(ringbuf);
+    if let Some(histogram) = histogram.as_mut() {
+        // pick up the samples counted since the last tick
+        if let Err(e) = read_histogram(histogram, &summary_mutex) {
+            warn!("Failed to read histogram: {}", e);
+        }
+    }
+
+    let _ = shutdown_tx.send(Some(time::Instant::now() + FLUSH_TIMEOUT));
+    match time::timeout(SHUTDOWN_DEADLINE, store_task).await {
+        Ok(Ok(())) => println!("Flushed partial window"),
+        Ok(Err(e)) => warn!("Store task failed: {}", e),
+        Err(_) => warn!(
+            "Partial window not flushed within {:?}, exiting anyway",
+            SHUTDOWN_DEADLINE
+        ),
+    }
+
     Ok(())
 }
 
-fn u32_to_ip(ip: u32) -> String {
-    Ipv4Addr::from(ip).to_string()
+/// Stores the summaries of every window as it completes. Once `shutdown`
+/// carries a flush deadline the window in progress is stored marked as
+/// partial and the task ends; whatever is not stored by the deadline, also of
+/// a window that was being stored when it arrived, is spooled.
+async fn store_windows(
+    svc: Service,
+    spool: Arc<Mutex<Spool>>,
+    summary_mutex: Arc<Mutex<Summaries>>,
+    drops: Arc<PerCpuArray<MapData, u64>>,
+    window: TimeDelta,
+    agg_level: String,
+    mut shutdown: watch::Receiver<Option<time::Instant>>,
+) {
+    // the kernel counters only ever grow, each window records the difference
+    let mut drops_seen = 0;
+
+    loop {
+        let now = Utc::now();
+        let window_start = now.duration_trunc(window).unwrap_or(now);
+        let window_end = window_start + window;
+        // shutdown may have been requested while the last window was stored
+        let partial = shutdown.borrow().is_some()
+            || tokio::select! {
+                _ = time::sleep((window_end - now).to_std().unwrap_or_default()) => false,
+                _ = shutdown.changed() => true,
+            };
+        // cut short by the flush deadline once shutdown is requested
+        let deadline = time::Instant::now() + STORE_TIMEOUT;
+
+        // swap in empty summaries so each record only covers its own window
+        let summaries = lock_summaries(&summary_mutex).take();
+
+        let dropped = match read_drops(&drops) {
+            Ok(total) => window_drops(&mut drops_seen, total),
+            Err(e) => {
+                warn!("Failed to read drop counters: {}", e);
+                0
+            }
+        };
+
+        let samples = summaries.total().count();
+        let records = window_records(&svc, &summaries, &agg_level, window_start, dropped, partial);
+
+        // once the store fails the rest of the window is spooled without
+        // further attempts, retrying every record would keep the next window
+        // from being swapped in for as long as the outage lasts
+        let mut store_down = false;
+        let (mut digests, mut spooled) = (0, 0);
+        for record in records {
+            digests += 1;
+            match store_down {
+                true => {
+                    if let Err(e) = spool_record(&spool, &record) {
+                        warn!("Failed to spool t digest {}: {}", record.key, e);
+                    }
+                }
+                false => {
+                    store_down =
+                        !store_or_spool(&svc, &spool, &record, deadline, &mut shutdown).await
+                }
+            }
+            if store_down {
+                spooled += 1;
+            }
+        }
+        if store_down {
+            warn!(
+                "Store unavailable, spooled the rest of window {}",
+                window_start
+            );
+        }
+        println!(
+            "Stored window {} ({} samples, {} dropped{}): {} digests, {} spooled",
+            window_start,
+            samples,
+            dropped,
+            if partial { ", partial" } else { "" },
+            digests - spooled,
+            spooled
+        );
+
+        if partial {
+            return;
+        }
+    }
+}
+
+/// The records of one window: the node-wide digest, which carries the events
+/// dropped in the window, one per destination and one per TCP metric, all
+/// keyed apart from the complete window's when `partial`
+fn window_records(
+    svc: &Service,
+    summaries: &Summaries,
+    agg_level: &str,
+    window_start: DateTime<Utc>,
+    dropped: u64,
+    partial: bool,
+) -> Vec<TDigestRecord> {
+    std::iter::once((None, summaries.total()))
+        .chain(
+            summaries
+                .destinations()
+                .map(|(dst, summary)| (Some(dst.to_string()), summary)),
+        )
+        .map(|(dimension, summary)| {
+            TDigestRecord {
+                dropped: if dimension.is_none() { dropped } else { 0 },
+                ..svc.record(
+                    agg_level.to_string(),
+                    window_start,
+                    dimension,
+                    summary.digest(),
+                    summary.count(),
+                )
+            }
+            .with_partial(partial)
+        })
+        .chain(summaries.metrics().map(|(metric, summary)| {
+            svc.record(
+                agg_level.to_string(),
+                window_start,
+                None,
+                summary.digest(),
+                summary.count(),
+            )
+            .with_metric(metric.name())
+            .with_partial(partial)
+        }))
+        .collect()
+}
+
+/// Stores a record, spooling it when the store fails in a way a later retry
+/// may fix or when `deadline`, or the flush deadline `shutdown` carries, passes
+/// first. Returns false when it was spooled.
+async fn store_or_spool(
+    svc: &Service,
+    spool: &Mutex<Spool>,
+    record: &TDigestRecord,
+    deadline: time::Instant,
+    shutdown: &mut watch::Receiver<Option<time::Instant>>,
+) -> bool {
+    let store = store_with_retry(svc, record);
+    tokio::pin!(store);
+    let result = loop {
+        let deadline = match *shutdown.borrow() {
+            Some(flush) => deadline.min(flush),
+            None => deadline,
+        };
+        tokio::select! {
+            result = &mut store => break result,
+            _ = time::sleep_until(deadline) => {
+                break Err(rtt_tdigest::Error::Unavailable("timed out".into()))
+            }
+            // shutdown requested mid-store, wait no longer than its deadline
+            Ok(()) = shutdown.changed() => {}
+        }
+    };
+
+    match result {
+        Ok(_) => true,
+        Err(e) if e.is_retryable() => {
+            warn!("Failed to store t digest {}, spooling: {}", record.key, e);
+            if let Err(e) = spool_record(spool, record) {
+                warn!("Failed to spool t digest {}: {}", record.key, e);
+            }
+            false
+        }
+        Err(e) => {
+            warn!("Failed to store t digest {}: {}", record.key, e);
+            true
+        }
+    }
+}
+
+/// Stores one window's digest, retrying with backoff while the store reports a
+/// transient failure. Other errors will not go away by retrying and are returned as is.
+async fn store_with_retry(svc: &Service, record: &TDigestRecord) -> rtt_tdigest::Result<()> {
+    let mut backoff = STORE_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match svc.store_record(record).await {
+            Err(e) if e.is_retryable() && attempt < STORE_ATTEMPTS => {
+                warn!(
+                    "Store attempt {} failed, retrying in {:?}: {}",
+                    attempt, backoff, e
+                );
+                time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn spool_record(spool: &Mutex<Spool>, record: &TDigestRecord) -> anyhow::Result<()> {
+    spool
+        .lock()
+        .map_err(|e| anyhow!("spool lock poisoned: {}", e))?
+        .push(record)
+}
+
+/// Sends spooled records to the store, backing off while it keeps failing
+async fn drain_spool(svc: Service, spool: Arc<Mutex<Spool>>) {
+    let mut delay = DRAIN_INTERVAL;
+
+    loop {
+        time::sleep(delay).await;
+
+        delay = match drain_once(&svc, &spool).await {
+            Ok(()) => DRAIN_INTERVAL,
+            Err(e) => {
+                let delay = (delay * 2).min(DRAIN_MAX_BACKOFF);
+                warn!("Draining spool failed, retrying in {:?}: {}", delay, e);
+                delay
+            }
+        };
+    }
+}
+
+/// Stores spooled segments oldest first until the spool is empty or the store
+/// fails with a retryable error
+async fn drain_once(svc: &Service, spool: &Mutex<Spool>) -> anyhow::Result<()> {
+    loop {
+        let segment = spool
+            .lock()
+            .map_err(|e| anyhow!("spool lock poisoned: {}", e))?
+            .oldest()?;
+        let Some(segment) = segment else {
+            return Ok(());
+        };
+
+        for record in &segment.records {
+            match svc.store_record(record).await {
+                Ok(_) => {}
+                Err(e) if e.is_retryable() => return Err(e.into()),
+                Err(e) => warn!("Dropping spooled t digest {}: {}", record.key, e),
+            }
+        }
+
+        let mut spool = spool
+            .lock()
+            .map_err(|e| anyhow!("spool lock poisoned: {}", e))?;
+        spool.remove(&segment.path)?;
+        println!(
+            "Drained {} spooled digests, {} bytes left",
+            segment.records.len(),
+            spool.len_bytes()
+        );
+    }
+}
+
+/// Adds the samples the kernel counted since the last read to the host-wide
+/// summary, returning how many there were
+fn read_histogram(
+    histogram: &mut KernelHistogram,
+    summary_mutex: &Mutex<Summaries>,
+) -> anyhow::Result<u64> {
+    let buckets = histogram.read()?;
+    lock_summaries(summary_mutex).add_histogram(&buckets);
+
+    Ok(buckets.iter().map(|(_, samples)| samples).sum())
+}
+
+/// Locks the summaries, recovering them from a panic in another holder. Every
+/// update leaves them consistent, at worst the sample being added is lost, and
+/// skipping the lock instead would leave the ring buffer ready and unread.
+fn lock_summaries(summary_mutex: &Mutex<Summaries>) -> MutexGuard<'_, Summaries> {
+    summary_mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn log_rate(processed: u64, start: Instant, drops: &PerCpuArray<MapData, u64>) {
+    let elapsed = start.elapsed().as_secs_f64();
+    let rate = processed as f64 / elapsed;
+    let dropped = read_drops(drops).unwrap_or_else(|e| {
+        warn!("Failed to read drop counters: {}", e);
+        0
+    });
+    println!(
+        "📊 {} samples in {:.1}s = {:.1} events/sec, {} dropped",
+        processed, elapsed, rate, dropped
+    );
+}
+
+/// Drops since the last window given the kernel's running `total`, which
+/// only resets when the program is loaded again and then counts from zero
+fn window_drops(seen: &mut u64, total: u64) -> u64 {
+    match total.checked_sub(std::mem::replace(seen, total)) {
+        Some(dropped) => dropped,
+        None => total,
+    }
+}
+
+/// Events the eBPF program could not reserve ring buffer space for, summed over
+/// all CPUs since it was loaded
+fn read_drops(drops: &PerCpuArray<MapData, u64>) -> anyhow::Result<u64> {
+    Ok(drops.get(&0, 0)?.iter().sum())
+}
+
+/// Source of ring buffer records
+trait Records {
+    fn next_record(&mut self) -> Option<impl Deref<Target = [u8]> + '_>;
+}
+
+impl Records for RingBuf<MapData> {
+    fn next_record(&mut self) -> Option<impl Deref<Target = [u8]> + '_> {
+        self.next()
+    }
+}
+
+/// Adds up to `max` records to the summaries, passing each event added to
+/// `on_event`, and returns how many were read, invalid ones included. Fewer
+/// than `max` means the records ran out.
+fn read_batch(
+    records: &mut impl Records,
+    summaries: &mut Summaries,
+    max: usize,
+    mut on_event: impl FnMut(&RttEvent, &mut Summaries),
+) -> usize {
+    let mut batch = 0;
+    while batch < max {
+        let Some(data) = records.next_record() else {
+            break;
+        };
+        batch += 1;
+
+        match read_event(&data) {
+            Ok(event) => {
+                summaries.add(&event);
+                on_event(&event, summaries);
+            }
+            Err(e) => warn!("Dropping ring buffer record: {}", e),
+        }
+    }
+
+    batch
+}
+
+/// Copies a ring buffer record into an `RttEvent`, rejecting records whose
+/// length or header disagree with the event layout this binary was built with.
+/// Short records leave `metrics` zeroed.
+fn read_event(data: &[u8]) -> anyhow::Result<RttEvent> {
+    if data.len() != RttEvent::SIZE && data.len() != RttEvent::SHORT_SIZE {
+        return Err(anyhow!(
+            "unexpected record length {} (want {} or {})",
+            data.len(),
+            RttEvent::SIZE,
+            RttEvent::SHORT_SIZE
+        ));
+    }
+
+    let mut record = [0u8; RttEvent::SIZE];
+    record[..data.len()].copy_from_slice(data);
+    let event = unsafe { ptr::read_unaligned(record.as_ptr() as *const RttEvent) };
+    let short_with_metrics = data.len() == RttEvent::SHORT_SIZE && event.metrics().is_some();
+    if event.header.version != EVENT_VERSION
+        || usize::from(event.header.size) != data.len()
+        || short_with_metrics
+    {
+        return Err(anyhow!(
+            "unexpected event header version={} size={} flags={:#x} (want version={} size={})",
+            event.header.version,
+            event.header.size,
+            event.flags,
+            EVENT_VERSION,
+            data.len()
+        ));
+    }
+
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use chrono::TimeZone;
+    use rtt_quantiles_common::{EventHeader, TcpMetrics, AF_INET, EVENT_TCP_METRICS};
+    use rtt_tdigest::{DigestQuery, DigestStore, ListBy, Listing};
+    use std::{collections::VecDeque, num::NonZeroUsize};
+
+    /// Store that never answers, as one would during an outage
+    struct HangingStore;
+
+    #[async_trait]
+    impl DigestStore for HangingStore {
+        async fn store(&self, _: &TDigestRecord) -> rtt_tdigest::Result<()> {
+            std::future::pending().await
+        }
+
+        async fn query(&self, _: &DigestQuery) -> rtt_tdigest::Result<Vec<TDigestRecord>> {
+            std::future::pending().await
+        }
+
+        async fn list(&self, _: &ListBy) -> rtt_tdigest::Result<Vec<Listing>> {
+            std::future::pending().await
+        }
+
+        async fn app_exists(&self, _: &str) -> rtt_tdigest::Result<bool> {
+            std::future::pending().await
+        }
+
+        async fn migrate_keys(&self) -> rtt_tdigest::Result<usize> {
+            std::future::pending().await
+        }
+    }
+
+    impl Records for VecDeque<Vec<u8>> {
+        fn next_record(&mut self) -> Option<impl Deref<Target = [u8]> + '_> {
+            self.pop_front()
+        }
+    }
+
+    fn service() -> Service {
+        Service::new(Arc::new(HangingStore), "app".into(), "node".into())
+    }
+
+    fn event(metrics: Option<TcpMetrics>) -> RttEvent {
+        let mut dst_addr = [0; 16];
+        dst_addr[..4].copy_from_slice(&[10, 0, 0, 1]);
+
+        RttEvent {
+            header: match metrics {
+                Some(_) => EventHeader::new::<RttEvent>(),
+                None => EventHeader::sized(RttEvent::SHORT_SIZE),
+            },
+            srtt_us: 1500,
+            family: AF_INET,
+            protocol: 6,
+            src_port: 40000,
+            dst_port: 443,
+            src_addr: [0; 16],
+            dst_addr,
+            flags: match metrics {
+                Some(_) => EVENT_TCP_METRICS,
+                None => 0,
+            },
+            metrics: metrics.unwrap_or_default(),
+        }
+    }
+
+    fn bytes(event: &RttEvent) -> Vec<u8> {
+        let len = usize::from(event.header.size);
+        let all = unsafe {
+            std::slice::from_raw_parts(event as *const RttEvent as *const u8, RttEvent::SIZE)
+        };
+        all[..len].to_vec()
+    }
+
+    #[test]
+    fn short_events_are_read_without_metrics() {
+        let read = read_event(&bytes(&event(None))).unwrap();
+
+        assert_eq!(read.srtt_us, 1500);
+        assert_eq!(read.dst(), "10.0.0.1:443".parse().unwrap());
+        assert!(read.metrics().is_none());
+    }
+
+    #[test]
+    fn full_events_carry_metrics() {
+        let metrics = TcpMetrics {
+            snd_cwnd: 10,
+            ..TcpMetrics::default()
+        };
+
+        let read = read_event(&bytes(&event(Some(metrics)))).unwrap();
+        assert_eq!(read.metrics().map(|m| m.snd_cwnd), Some(10));
+    }
+
+    #[test]
+    fn mismatched_records_are_rejected() {
+        let full = bytes(&event(Some(TcpMetrics::default())));
+        let short = bytes(&event(None));
+
+        // wrong lengths, and lengths that disagree with the header
+        assert!(read_event(&full[..40]).is_err());
+        assert!(read_event(&full[..RttEvent::SHORT_SIZE]).is_err());
+        let mut padded = short.clone();
+        padded.resize(RttEvent::SIZE, 0);
+        assert!(read_event(&padded).is_err());
+
+        let mut old_version = short;
+        old_version[..2].copy_from_slice(&(EVENT_VERSION - 1).to_ne_bytes());
+        assert!(read_event(&old_version).is_err());
+    }
+
+    #[test]
+    fn partial_windows_are_keyed_apart_and_carry_the_drops_once() {
+        let mut summaries = Summaries::new(NonZeroUsize::new(8).unwrap(), false, 100);
+        summaries.add(&event(Some(TcpMetrics {
+            snd_cwnd: 10,
+            ..TcpMetrics::default()
+        })));
+        let window_start = Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap();
+
+        let records = window_records(&service(), &summaries, "1m", window_start, 5, true);
+        assert!(records.len() > 2, "node, destination and metric digests");
+        for record in &records {
+            assert!(record.partial);
+            assert!(record.key.ends_with("#partial"), "{}", record.key);
+            assert_eq!(record.key, record.expected_key());
+        }
+        let dropped: Vec<_> = records.iter().map(|r| r.dropped).collect();
+        assert_eq!(dropped[0], 5);
+        assert!(records[0].dimension.is_none());
+        assert!(dropped[1..].iter().all(|&d| d == 0));
+
+        let complete = window_records(&service(), &summaries, "1m", window_start, 5, false);
+        assert!(complete.iter().all(|r| !r.key.ends_with("#partial")));
+    }
+
+    #[tokio::test]
+    async fn records_are_spooled_once_the_flush_deadline_passes() {
+        let dir = std::env::temp_dir().join(format!("rtt-flush-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let spool = Mutex::new(Spool::open(&dir, u64::MAX).unwrap());
+        let mut summary = rtt_tdigest::Summary::new();
+        summary.add_value(1500);
+        let record = TDigestRecord::new(
+            "app",
+            "1m",
+            "node",
+            Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap(),
+            None,
+            summary.digest(),
+            summary.count(),
+        )
+        .with_partial(true);
+
+        // shutdown already requested, the store deadline alone would not pass
+        let (_shutdown_tx, mut shutdown) = watch::channel(Some(time::Instant::now()));
+        let deadline = time::Instant::now() + STORE_TIMEOUT;
+        let stored = store_or_spool(&service(), &spool, &record, deadline, &mut shutdown).await;
+        assert!(!stored);
+
+        let segment = spool.lock().unwrap().oldest().unwrap().unwrap();
+        assert_eq!(segment.records.len(), 1);
+        assert_eq!(segment.records[0].key, record.key);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn batches_stop_at_the_limit_and_when_records_run_out() {
+        let mut summaries = Summaries::new(NonZeroUsize::new(8).unwrap(), false, 100);
+        let mut records: VecDeque<_> = (0..5).map(|_| bytes(&event(None))).collect();
+        // invalid records count towards the batch without being added
+        records[1].truncate(40);
+
+        let mut seen = 0;
+        let batch = read_batch(&mut records, &mut summaries, 3, |_, _| seen += 1);
+        assert_eq!((batch, seen), (3, 2));
+        assert_eq!(records.len(), 2);
+        assert_eq!(summaries.total().count(), 2);
+
+        let batch = read_batch(&mut records, &mut summaries, 3, |_, _| {});
+        assert_eq!(batch, 2, "short batch once the records run out");
+        assert_eq!(summaries.total().count(), 4);
+        assert_eq!(read_batch(&mut records, &mut summaries, 3, |_, _| {}), 0);
+    }
+
+    #[test]
+    fn windows_count_the_drops_since_the_last_one() {
+        let mut seen = 0;
+
+        assert_eq!(window_drops(&mut seen, 0), 0);
+        assert_eq!(window_drops(&mut seen, 7), 7);
+        assert_eq!(window_drops(&mut seen, 7), 0);
+        assert_eq!(window_drops(&mut seen, 10), 3);
+        // counters of a reloaded program start over
+        assert_eq!(window_drops(&mut seen, 2), 2);
+        assert_eq!(window_drops(&mut seen, 5), 3);
+    }
+
+    #[test]
+    fn metrics_are_only_read_when_flagged() {
+        let full = event(Some(TcpMetrics {
+            snd_cwnd: 10,
+            ..TcpMetrics::default()
+        }));
+        assert_eq!(&bytes(&full)[..RttEvent::SHORT_SIZE], full.short());
+
+        // a full length record the probe did not flag still has no metrics
+        let unflagged = RttEvent { flags: 0, ..full };
+        let read = read_event(&bytes(&unflagged)).unwrap();
+        assert!(read.metrics().is_none());
+        assert_eq!(read.srtt_us, 1500);
+    }
 }
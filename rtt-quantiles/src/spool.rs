@@ -0,0 +1,283 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use log::warn;
+use rtt_tdigest::TDigestRecord;
+
+/// Size at which the segment being appended to is closed and a new one started
+const SEGMENT_BYTES: u64 = 1024 * 1024;
+
+/// Records that could not be stored, kept on disk until the store is healthy
+/// again. Records are appended as JSON lines to numbered segment files in the
+/// spool directory. When the spool grows past `max_bytes` whole segments are
+/// evicted, oldest first.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Segment files and their sizes, oldest first
+    segments: VecDeque<(PathBuf, u64)>,
+    /// Open handle on the last segment while it is being appended to
+    head: Option<File>,
+    next_seq: u64,
+}
+
+/// Records read back from one segment
+pub struct Segment {
+    pub path: PathBuf,
+    pub records: Vec<TDigestRecord>,
+}
+
+impl Spool {
+    /// Open or create a spool directory, picking up segments left by a
+    /// previous run
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create spool dir {}", dir.display()))?;
+
+        let mut found = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if let Some(seq) = segment_seq(&path) {
+                found.push((seq, path.metadata()?.len(), path));
+            }
+        }
+        found.sort();
+
+        let next_seq = found.last().map_or(0, |(seq, _, _)| seq + 1);
+        let segments = found
+            .into_iter()
+            .map(|(_, len, path)| (path, len))
+            .collect();
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            segments,
+            head: None,
+            next_seq,
+        })
+    }
+
+    /// Bytes currently spooled
+    pub fn len_bytes(&self) -> u64 {
+        self.segments.iter().map(|(_, len)| len).sum()
+    }
+
+    /// Append a record, synced to disk before returning, then evict the
+    /// oldest segments if the spool is over its size cap
+    pub fn push(&mut self, record: &TDigestRecord) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let full = self
+            .segments
+            .back()
+            .is_none_or(|(_, len)| len + line.len() as u64 > SEGMENT_BYTES);
+        if self.head.is_none() || full {
+            self.start_segment()?;
+        }
+
+        let (head, (_, len)) = match (self.head.as_mut(), self.segments.back_mut()) {
+            (Some(head), Some(segment)) => (head, segment),
+            _ => unreachable!("start_segment opens a head segment"),
+        };
+        head.write_all(&line)?;
+        head.sync_data()?;
+        *len += line.len() as u64;
+
+        self.evict()
+    }
+
+    /// Read the oldest segment. It stays on disk until passed to `remove`, so
+    /// a crash while draining replays it; stores are keyed by window, which
+    /// makes storing a record twice harmless.
+    pub fn oldest(&mut self) -> anyhow::Result<Option<Segment>> {
+        let Some((path, _)) = self.segments.front() else {
+            return Ok(None);
+        };
+        let path = path.clone();
+        if self.segments.len() == 1 {
+            // stop appending to the segment being drained
+            self.head = None;
+        }
+
+        let mut records = Vec::new();
+        for (n, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+            // a torn last line is left behind by a crash mid-append
+            match serde_json::from_str(&line?) {
+                Ok(record) => records.push(record),
+                Err(e) => warn!("Skipping line {} of {}: {}", n + 1, path.display(), e),
+            }
+        }
+
+        Ok(Some(Segment { path, records }))
+    }
+
+    /// Delete a drained segment
+    pub fn remove(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.segments.retain(|(p, _)| p != path);
+        match fs::remove_file(path) {
+            // already evicted while it was being drained
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    fn start_segment(&mut self) -> anyhow::Result<()> {
+        let path = self.dir.join(format!("{:020}.jsonl", self.next_seq));
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("failed to create spool segment {}", path.display()))?;
+
+        self.next_seq += 1;
+        self.segments.push_back((path, 0));
+        self.head = Some(file);
+
+        Ok(())
+    }
+
+    fn evict(&mut self) -> anyhow::Result<()> {
+        // the head segment is never evicted, it holds the newest records
+        while self.len_bytes() > self.max_bytes && self.segments.len() > 1 {
+            if let Some((path, len)) = self.segments.pop_front() {
+                warn!(
+                    "Spool over {} bytes, dropping {} ({} bytes)",
+                    self.max_bytes,
+                    path.display(),
+                    len
+                );
+                fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn segment_seq(path: &Path) -> Option<u64> {
+    if path.extension()? != "jsonl" {
+        return None;
+    }
+
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use rtt_tdigest::Summary;
+
+    /// Empty spool directory unique to the test
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rtt-spool-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(minute: u32) -> TDigestRecord {
+        let mut summary = Summary::new();
+        summary.add_value(1000 + minute);
+        let created_at = Utc.with_ymd_and_hms(2023, 6, 1, 0, minute, 0).unwrap();
+
+        TDigestRecord::new(
+            "app",
+            "1m",
+            "node",
+            created_at,
+            None,
+            summary.digest(),
+            summary.count(),
+        )
+    }
+
+    fn keys(segment: &Segment) -> Vec<String> {
+        segment.records.iter().map(|r| r.key.clone()).collect()
+    }
+
+    #[test]
+    fn records_drain_in_order_and_survive_reopening() {
+        let dir = spool_dir("drain");
+        let mut spool = Spool::open(&dir, u64::MAX).unwrap();
+        assert!(spool.oldest().unwrap().is_none());
+
+        spool.push(&record(0)).unwrap();
+        spool.push(&record(1)).unwrap();
+        assert!(spool.len_bytes() > 0);
+
+        let mut spool = Spool::open(&dir, u64::MAX).unwrap();
+        let segment = spool.oldest().unwrap().unwrap();
+        assert_eq!(keys(&segment), [record(0).key, record(1).key]);
+
+        // records pushed while draining go to a new segment
+        spool.push(&record(2)).unwrap();
+        spool.remove(&segment.path).unwrap();
+        let segment = spool.oldest().unwrap().unwrap();
+        assert_eq!(keys(&segment), [record(2).key]);
+
+        spool.remove(&segment.path).unwrap();
+        assert!(spool.oldest().unwrap().is_none());
+        assert_eq!(spool.len_bytes(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_last_line_is_skipped() {
+        let dir = spool_dir("torn");
+        let mut spool = Spool::open(&dir, u64::MAX).unwrap();
+        spool.push(&record(0)).unwrap();
+
+        let path = spool.oldest().unwrap().unwrap().path;
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"key":"app:1m:node"#).unwrap();
+
+        let segment = Spool::open(&dir, u64::MAX)
+            .unwrap()
+            .oldest()
+            .unwrap()
+            .unwrap();
+        assert_eq!(keys(&segment), [record(0).key]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oldest_segments_are_evicted_over_the_cap() {
+        let dir = spool_dir("evict");
+        let mut spool = Spool::open(&dir, u64::MAX).unwrap();
+        spool.push(&record(0)).unwrap();
+        let one_record = spool.len_bytes();
+
+        // room for two records but not three, records differ by a few bytes
+        let max_bytes = one_record * 5 / 2;
+        // reopen so each push below starts a new segment
+        let mut spool = Spool::open(&dir, max_bytes).unwrap();
+        spool.push(&record(1)).unwrap();
+        let mut spool = Spool::open(&dir, max_bytes).unwrap();
+        spool.push(&record(2)).unwrap();
+
+        assert!(spool.len_bytes() <= max_bytes);
+        let segment = spool.oldest().unwrap().unwrap();
+        assert_eq!(keys(&segment), [record(1).key]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn head_segment_is_kept_even_over_the_cap() {
+        let dir = spool_dir("head");
+        let mut spool = Spool::open(&dir, 1).unwrap();
+        spool.push(&record(0)).unwrap();
+        spool.push(&record(1)).unwrap();
+
+        let segment = spool.oldest().unwrap().unwrap();
+        assert_eq!(keys(&segment), [record(0).key, record(1).key]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
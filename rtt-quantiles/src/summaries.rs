@@ -0,0 +1,248 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+};
+
+use lru::LruCache;
+use rtt_quantiles_common::{RttEvent, TcpMetrics};
+use rtt_tdigest::{metric, Summary};
+
+/// Remote end a digest is kept for, optionally narrowed to a single port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Destination {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => SocketAddr::new(self.ip, port).fmt(f),
+            None => self.ip.fmt(f),
+        }
+    }
+}
+
+/// `tcp_sock` metric besides srtt kept as a host-wide digest
+#[derive(Debug, Clone, Copy)]
+pub enum Metric {
+    Mdev,
+    RttMin,
+    SndCwnd,
+    TotalRetrans,
+    PacketsOut,
+    DeliveryRate,
+}
+
+impl Metric {
+    const ALL: [Metric; 6] = [
+        Metric::Mdev,
+        Metric::RttMin,
+        Metric::SndCwnd,
+        Metric::TotalRetrans,
+        Metric::PacketsOut,
+        Metric::DeliveryRate,
+    ];
+
+    /// Name the metric's digests are stored under
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::Mdev => metric::MDEV,
+            Metric::RttMin => metric::RTT_MIN,
+            Metric::SndCwnd => metric::SND_CWND,
+            Metric::TotalRetrans => metric::TOTAL_RETRANS,
+            Metric::PacketsOut => metric::PACKETS_OUT,
+            Metric::DeliveryRate => metric::DELIVERY_RATE,
+        }
+    }
+
+    /// Empty summary scaled to the unit `metric::unit` gives for the metric
+    fn summary(self, compression: usize) -> Summary {
+        match self {
+            // microseconds, kept in milliseconds like srtt
+            Metric::Mdev | Metric::RttMin => Summary::with_compression(compression),
+            // kbit/s, kept in Mbit/s
+            Metric::DeliveryRate => Summary::with_scale(compression, 1000.0),
+            Metric::SndCwnd | Metric::TotalRetrans | Metric::PacketsOut => {
+                Summary::with_scale(compression, 1.0)
+            }
+        }
+    }
+
+    /// The metric's sample, none if the kernel has not measured it yet
+    fn value(self, metrics: &TcpMetrics) -> Option<u32> {
+        match self {
+            Metric::Mdev => Some(metrics.mdev_us),
+            Metric::RttMin => (metrics.rtt_min_us != u32::MAX).then_some(metrics.rtt_min_us),
+            Metric::SndCwnd => Some(metrics.snd_cwnd),
+            // the running total of the connection, not a per-sample delta
+            Metric::TotalRetrans => Some(metrics.total_retrans),
+            Metric::PacketsOut => Some(metrics.packets_out),
+            Metric::DeliveryRate => {
+                if metrics.rate_interval_us == 0 {
+                    return None;
+                }
+                // bits per µs are Mbit/s, a thousand times that kbit/s
+                let bits = u64::from(metrics.rate_delivered) * u64::from(metrics.mss_cache);
+                let kbps = bits.saturating_mul(8 * 1000) / u64::from(metrics.rate_interval_us);
+                Some(kbps.try_into().unwrap_or(u32::MAX))
+            }
+        }
+    }
+}
+
+/// Host-wide summary plus a bounded set of per-destination summaries. When the
+/// destination set is full the least recently seen destination is evicted.
+pub struct Summaries {
+    total: Summary,
+    destinations: LruCache<Destination, Summary>,
+    /// Host-wide summaries of the extra metrics, empty until an event
+    /// carrying them is added
+    metrics: Vec<(Metric, Summary)>,
+    by_port: bool,
+    compression: usize,
+}
+
+impl Summaries {
+    pub fn new(max_destinations: NonZeroUsize, by_port: bool, compression: usize) -> Self {
+        Self {
+            total: Summary::with_compression(compression),
+            destinations: LruCache::new(max_destinations),
+            metrics: Vec::new(),
+            by_port,
+            compression,
+        }
+    }
+
+    /// Swap in empty summaries, returning the ones collected so far
+    pub fn take(&mut self) -> Self {
+        let empty = Self::new(self.destinations.cap(), self.by_port, self.compression);
+        std::mem::replace(self, empty)
+    }
+
+    /// Add the event's rtt to the host-wide and destination summaries
+    pub fn add(&mut self, event: &RttEvent) {
+        let dst = event.dst();
+        let destination = Destination {
+            ip: dst.ip(),
+            port: self.by_port.then_some(dst.port()),
+        };
+
+        self.total.add_rtt(event.srtt_us);
+        self.destinations
+            .get_or_insert_mut(destination, || Summary::with_compression(self.compression))
+            .add_rtt(event.srtt_us);
+
+        if let Some(tcp) = event.metrics() {
+            if self.metrics.is_empty() {
+                self.metrics = Metric::ALL
+                    .iter()
+                    .map(|&metric| (metric, metric.summary(self.compression)))
+                    .collect();
+            }
+            for (metric, summary) in &mut self.metrics {
+                if let Some(value) = metric.value(tcp) {
+                    summary.add_value(value);
+                }
+            }
+        }
+    }
+
+    /// Add samples counted by the in-kernel histogram. They carry no
+    /// destination, so only the host-wide summary is updated.
+    pub fn add_histogram(&mut self, buckets: &[(u32, u64)]) {
+        self.total.add_histogram(buckets);
+    }
+
+    pub fn total(&self) -> &Summary {
+        &self.total
+    }
+
+    /// The host-wide summary, mutable to read its quantiles
+    pub fn total_mut(&mut self) -> &mut Summary {
+        &mut self.total
+    }
+
+    /// Summaries of the extra metrics, if events carried them
+    pub fn metrics(&self) -> impl Iterator<Item = (Metric, &Summary)> {
+        self.metrics
+            .iter()
+            .map(|(metric, summary)| (*metric, summary))
+    }
+
+    /// Destinations from most to least recently seen
+    pub fn destinations(&self) -> impl Iterator<Item = (&Destination, &Summary)> {
+        self.destinations.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics() -> TcpMetrics {
+        TcpMetrics {
+            mdev_us: 250,
+            rtt_min_us: 900,
+            snd_cwnd: 10,
+            total_retrans: 3,
+            packets_out: 4,
+            mss_cache: 1448,
+            rate_delivered: 10,
+            rate_interval_us: 1000,
+        }
+    }
+
+    #[test]
+    fn values_are_read_from_their_fields() {
+        let metrics = metrics();
+
+        assert_eq!(Metric::Mdev.value(&metrics), Some(250));
+        assert_eq!(Metric::RttMin.value(&metrics), Some(900));
+        assert_eq!(Metric::SndCwnd.value(&metrics), Some(10));
+        assert_eq!(Metric::TotalRetrans.value(&metrics), Some(3));
+        assert_eq!(Metric::PacketsOut.value(&metrics), Some(4));
+    }
+
+    #[test]
+    fn unmeasured_rtt_min_has_no_value() {
+        let metrics = TcpMetrics {
+            rtt_min_us: u32::MAX,
+            ..metrics()
+        };
+
+        assert_eq!(Metric::RttMin.value(&metrics), None);
+    }
+
+    #[test]
+    fn delivery_rate_is_in_kbit_per_second() {
+        // 10 segments of 1448 bytes in 1ms is 115.84 Mbit/s
+        assert_eq!(Metric::DeliveryRate.value(&metrics()), Some(115_840));
+
+        let idle = TcpMetrics {
+            rate_interval_us: 0,
+            ..metrics()
+        };
+        assert_eq!(Metric::DeliveryRate.value(&idle), None);
+    }
+
+    #[test]
+    fn delivery_rate_saturates_instead_of_overflowing() {
+        let metrics = TcpMetrics {
+            rate_delivered: u32::MAX,
+            mss_cache: u32::MAX,
+            rate_interval_us: 1,
+            ..metrics()
+        };
+
+        assert_eq!(Metric::DeliveryRate.value(&metrics), Some(u32::MAX));
+    }
+
+    #[test]
+    fn every_metric_has_a_unit() {
+        for metric in Metric::ALL {
+            assert!(metric::unit(metric.name()).is_some(), "{:?}", metric);
+        }
+    }
+}
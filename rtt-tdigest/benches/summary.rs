@@ -0,0 +1,61 @@
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use rtt_tdigest::Summary;
+use tdigest::TDigest;
+
+/// Pseudo random rtts between 0.1ms and ~100ms, deterministic across runs
+fn samples(n: usize) -> Vec<u32> {
+    let mut x: u32 = 0x9e37_79b9;
+    (0..n)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            100 + x % 100_000
+        })
+        .collect()
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("summary_insert");
+
+    for n in [1_000, 10_000, 100_000] {
+        let rtts = samples(n);
+        group.throughput(Throughput::Elements(n as u64));
+
+        // what Summary::add_rtt used to do for every sample
+        group.bench_with_input(BenchmarkId::new("merge_per_sample", n), &rtts, |b, rtts| {
+            b.iter(|| {
+                let mut digest = TDigest::new_with_size(100);
+                for &rtt in rtts {
+                    digest = digest.merge_unsorted(vec![rtt as f64 / 1000.0]);
+                }
+                black_box(digest)
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("add_rtt", n), &rtts, |b, rtts| {
+            b.iter(|| {
+                let mut summary = Summary::new();
+                for &rtt in rtts {
+                    summary.add_rtt(rtt);
+                }
+                black_box(summary.digest())
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("add_batch", n), &rtts, |b, rtts| {
+            b.iter(|| {
+                let mut summary = Summary::new();
+                for chunk in rtts.chunks(256) {
+                    summary.add_batch(chunk);
+                }
+                black_box(summary.digest())
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, insert);
+criterion_main!(benches);
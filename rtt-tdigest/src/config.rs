@@ -0,0 +1,71 @@
+use crate::dynamo::DynamoStore;
+use crate::error::{Error, Result};
+use crate::sqlite::SqliteStore;
+use crate::store::DigestStore;
+use aws_config::Region;
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_dynamodb::Client;
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const DEFAULT_SQLITE_PATH: &str = "rtt-tdigests.db";
+
+/// Storage backend, selected with `RTT_STORE` (`dynamodb` or `sqlite`). The
+/// SQLite database path is read from `RTT_SQLITE_PATH`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreConfig {
+    /// `region` overrides the region from the AWS environment
+    DynamoDb {
+        region: Option<String>,
+    },
+    Sqlite {
+        path: PathBuf,
+    },
+}
+
+impl StoreConfig {
+    pub fn from_env() -> Result<Self> {
+        let backend = env::var("RTT_STORE").unwrap_or_else(|_| "dynamodb".to_string());
+        let path = env::var("RTT_SQLITE_PATH").ok().map(PathBuf::from);
+
+        Self::parse(&backend, path, None)
+    }
+
+    /// Build a config from a backend name, an optional SQLite path and an
+    /// optional DynamoDB region
+    pub fn parse(
+        backend: &str,
+        sqlite_path: Option<PathBuf>,
+        region: Option<String>,
+    ) -> Result<Self> {
+        match backend {
+            "dynamodb" => Ok(StoreConfig::DynamoDb { region }),
+            "sqlite" => Ok(StoreConfig::Sqlite {
+                path: sqlite_path.unwrap_or_else(|| PathBuf::from(DEFAULT_SQLITE_PATH)),
+            }),
+            other => Err(Error::Config(format!(
+                "unknown store backend {:?}, expected dynamodb or sqlite",
+                other
+            ))),
+        }
+    }
+
+    /// Connect to the configured backend. DynamoDB credentials and, unless
+    /// set, the region come from the AWS environment, falling back to us-east-1.
+    pub async fn open(&self) -> Result<Arc<dyn DigestStore>> {
+        let store: Arc<dyn DigestStore> = match self {
+            StoreConfig::DynamoDb { region } => {
+                let region_provider =
+                    RegionProviderChain::first_try(region.clone().map(Region::new))
+                        .or_default_provider()
+                        .or_else("us-east-1");
+                let config = aws_config::from_env().region(region_provider).load().await;
+                Arc::new(DynamoStore::new(Client::new(&config)))
+            }
+            StoreConfig::Sqlite { path } => Arc::new(SqliteStore::open(path)?),
+        };
+
+        Ok(store)
+    }
+}
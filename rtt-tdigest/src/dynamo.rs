@@ -0,0 +1,516 @@
+use crate::error::{Error, Result, from_sdk};
+use crate::metric;
+use crate::record::TDigestRecord;
+use crate::store::{DigestQuery, DigestStore, ListBy, Listing, MAX_QUERY_NODES};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::Client;
+use aws_sdk_dynamodb::error::ProvideErrorMetadata;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use tdigest::TDigest;
+
+const TABLE_NAME: &str = "rtt-tdigests";
+/// One item per app and node or dimension, partitioned on `app` and sorted on
+/// `entry`, see `catalog_entry`
+const CATALOG_TABLE_NAME: &str = "rtt-tdigests-catalog";
+/// `catalog_entry` kinds
+const CATALOG_NODE: &str = "node";
+const CATALOG_DIMENSION: &str = "dimension";
+/// Global secondary index partitioned on `series` and sorted on `created_at`
+const SERIES_INDEX: &str = "series-created_at-index";
+
+/// Stores digest records in the `rtt-tdigests` DynamoDB table. Every item
+/// carries a `series` attribute (see `series_key`) so time ranges are read with
+/// a `Query` against `series-created_at-index` rather than a table scan.
+/// Apps, nodes and dimensions are listed from the `rtt-tdigests-catalog`
+/// table, which storing a `metric::SRTT` record keeps current.
+#[derive(Clone)]
+pub struct DynamoStore {
+    client: Client,
+}
+
+impl DynamoStore {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Widen the catalog item of an app's node or dimension to include a
+    /// window start, moving `last_seen` forward or else `first_seen` back
+    async fn touch_catalog(
+        &self,
+        app: &str,
+        kind: &str,
+        name: &str,
+        at: DateTime<Utc>,
+    ) -> Result<()> {
+        let updates = [
+            (
+                "SET last_seen = :at, first_seen = if_not_exists(first_seen, :at), #name = :name",
+                "attribute_not_exists(last_seen) OR last_seen < :at",
+            ),
+            ("SET first_seen = :at, #name = :name", "first_seen > :at"),
+        ];
+
+        for (update, condition) in updates {
+            match self
+                .client
+                .update_item()
+                .table_name(CATALOG_TABLE_NAME)
+                .key("app", AttributeValue::S(app.to_string()))
+                .key("entry", AttributeValue::S(catalog_entry(kind, name)))
+                .update_expression(update)
+                .condition_expression(condition)
+                // `name` is a reserved word
+                .expression_attribute_names("#name", "name")
+                .expression_attribute_values(":at", AttributeValue::S(at.to_rfc3339()))
+                .expression_attribute_values(":name", AttributeValue::S(name.to_string()))
+                .send()
+                .await
+            {
+                Ok(_) => return Ok(()),
+                // the window is already within the range on this side
+                Err(e) if e.code() == Some("ConditionalCheckFailedException") => {}
+                Err(e) => return Err(from_sdk(e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// List the nodes or dimensions of an app from its catalog partition
+    async fn list_catalog(&self, app: &str, kind: &str) -> Result<Vec<Listing>> {
+        let items = self
+            .client
+            .query()
+            .table_name(CATALOG_TABLE_NAME)
+            .key_condition_expression("app = :app AND begins_with(#entry, :kind)")
+            .expression_attribute_names("#entry", "entry")
+            .expression_attribute_values(":app", AttributeValue::S(app.to_string()))
+            .expression_attribute_values(":kind", AttributeValue::S(catalog_entry(kind, "")))
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .await
+            .map_err(from_sdk)?;
+
+        listings(&items, "name", "first_seen", "last_seen")
+    }
+}
+
+#[async_trait]
+impl DigestStore for DynamoStore {
+    async fn store(&self, record: &TDigestRecord) -> Result<()> {
+        let dynanmo_hashmap = record_to_item(record)?;
+
+        match self
+            .client
+            .put_item()
+            .table_name(TABLE_NAME)
+            .set_item(Some(dynanmo_hashmap))
+            .send()
+            .await
+        {
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Failed to store digest: {}", e);
+                return Err(from_sdk(e));
+            }
+        }
+
+        // srtt records are written once per window for the node and each of
+        // its dimensions, so they keep the catalog current without a write
+        // per metric. The digest is stored either way and `migrate_keys`
+        // rebuilds the catalog.
+        if record.metric == metric::SRTT {
+            let (kind, name) = catalog_name(record);
+            if let Err(e) = self
+                .touch_catalog(&record.app, kind, name, record.created_at)
+                .await
+            {
+                eprintln!(
+                    "Failed to update catalog for {}/{}: {}",
+                    record.app, name, e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn query(&self, query: &DigestQuery) -> Result<Vec<TDigestRecord>> {
+        let from_str = query.from.to_rfc3339();
+        let to_str = query.to.to_rfc3339();
+
+        // prepare expression attribute values
+        let mut expr_values = HashMap::new();
+        expr_values.insert(
+            ":series".to_string(),
+            AttributeValue::S(series_key(
+                &query.app,
+                &query.agg_level,
+                query.dimension.as_deref(),
+                &query.metric,
+            )),
+        );
+        expr_values.insert(":from".to_string(), AttributeValue::S(from_str));
+        expr_values.insert(":to".to_string(), AttributeValue::S(to_str));
+
+        // prepare expression attribute names
+        let mut expr_names = HashMap::new();
+        expr_names.insert("#series".to_string(), "series".to_string());
+        expr_names.insert("#created_at".to_string(), "created_at".to_string());
+
+        // the key condition selects the series, which includes the
+        // dimension, and the time range; nodes are filtered after the read
+        let key_condition = "#series = :series AND #created_at BETWEEN :from AND :to";
+        let mut node_filter = None;
+        // an IN condition takes at most 100 operands, longer node lists are
+        // applied to the deserialized records instead
+        let filter_nodes_after = query.nodes.len() > MAX_QUERY_NODES;
+        if !query.nodes.is_empty() && !filter_nodes_after {
+            expr_names.insert("#node_id".to_string(), "node_id".to_string());
+            let mut placeholders = Vec::with_capacity(query.nodes.len());
+            for (i, node) in query.nodes.iter().enumerate() {
+                let placeholder = format!(":node{}", i);
+                expr_values.insert(placeholder.clone(), AttributeValue::S(node.clone()));
+                placeholders.push(placeholder);
+            }
+            node_filter = Some(format!("#node_id IN ({})", placeholders.join(", ")));
+        }
+
+        // follow last_evaluated_key across every page of the result
+        let items = match self
+            .client
+            .query()
+            .table_name(TABLE_NAME)
+            .index_name(SERIES_INDEX)
+            .key_condition_expression(key_condition)
+            .set_filter_expression(node_filter)
+            .set_expression_attribute_names(Some(expr_names))
+            .set_expression_attribute_values(Some(expr_values))
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .await
+        {
+            Ok(items) => items,
+            Err(err) => {
+                eprintln!("DynamoDB error details: {:?}", err);
+                return Err(from_sdk(err));
+            }
+        };
+
+        // deserialize from results
+        let mut records = items
+            .iter()
+            .map(item_to_record)
+            .collect::<Result<Vec<_>>>()?;
+        if filter_nodes_after {
+            records.retain(|record| query.nodes.contains(&record.node_id));
+        }
+        Ok(records)
+    }
+
+    async fn list(&self, by: &ListBy) -> Result<Vec<Listing>> {
+        match by {
+            // the catalog holds one item per node and dimension of an app,
+            // so scanning it reads far less than scanning the digests
+            ListBy::Apps => {
+                let items = self
+                    .client
+                    .scan()
+                    .table_name(CATALOG_TABLE_NAME)
+                    .projection_expression("app, first_seen, last_seen")
+                    .into_paginator()
+                    .items()
+                    .send()
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .await
+                    .map_err(from_sdk)?;
+
+                listings(&items, "app", "first_seen", "last_seen")
+            }
+            ListBy::Nodes { app } => self.list_catalog(app, CATALOG_NODE).await,
+            ListBy::Dimensions { app } => self.list_catalog(app, CATALOG_DIMENSION).await,
+        }
+    }
+
+    async fn app_exists(&self, app: &str) -> Result<bool> {
+        // a single catalog item is enough, read one from the app's partition
+        let output = self
+            .client
+            .query()
+            .table_name(CATALOG_TABLE_NAME)
+            .key_condition_expression("app = :app")
+            .expression_attribute_values(":app", AttributeValue::S(app.to_string()))
+            .limit(1)
+            .send()
+            .await
+            .map_err(from_sdk)?;
+
+        Ok(output.count() > 0)
+    }
+
+    async fn migrate_keys(&self) -> Result<usize> {
+        // items are migrated as each page of the scan arrives rather than
+        // reading the whole table first
+        let mut items = self
+            .client
+            .scan()
+            .table_name(TABLE_NAME)
+            .into_paginator()
+            .items()
+            .send();
+
+        let mut catalog: BTreeMap<(String, &str, String), (DateTime<Utc>, DateTime<Utc>)> =
+            BTreeMap::new();
+        let mut migrated = 0;
+        while let Some(item) = items.next().await {
+            let item = item.map_err(from_sdk)?;
+            let mut record = item_to_record(&item)?;
+            if record.metric == metric::SRTT {
+                let (kind, name) = catalog_name(&record);
+                let (first, last) = catalog
+                    .entry((record.app.clone(), kind, name.to_string()))
+                    .or_insert((record.created_at, record.created_at));
+                *first = (*first).min(record.created_at);
+                *last = (*last).max(record.created_at);
+            }
+
+            let old_key = record.key.clone();
+            record.key = record.expected_key();
+            if record.key == old_key {
+                // same key, rewrite the item in place if its series predates
+                // the current `series_key`
+                if string_attr(&item, "series").ok() != Some(record_series_key(&record)) {
+                    self.store(&record).await?;
+                    migrated += 1;
+                }
+                continue;
+            }
+
+            // write the new item before removing the old one so an interrupted
+            // migration never loses a digest. An item already stored under the
+            // new key was written by a newer release and is kept.
+            match self
+                .client
+                .put_item()
+                .table_name(TABLE_NAME)
+                .set_item(Some(record_to_item(&record)?))
+                .condition_expression("attribute_not_exists(#key)")
+                .expression_attribute_names("#key", "key")
+                .send()
+                .await
+            {
+                Ok(_) => {}
+                Err(e) if e.code() == Some("ConditionalCheckFailedException") => {}
+                Err(e) => return Err(from_sdk(e)),
+            }
+            self.client
+                .delete_item()
+                .table_name(TABLE_NAME)
+                .key("key", AttributeValue::S(old_key))
+                .send()
+                .await
+                .map_err(from_sdk)?;
+            migrated += 1;
+        }
+
+        // rebuild the catalog from the digests, covering digests stored
+        // before it existed or while it could not be written
+        for ((app, kind, name), (first_seen, last_seen)) in catalog {
+            self.client
+                .put_item()
+                .table_name(CATALOG_TABLE_NAME)
+                .item("app", AttributeValue::S(app))
+                .item("entry", AttributeValue::S(catalog_entry(kind, &name)))
+                .item("name", AttributeValue::S(name))
+                .item("first_seen", AttributeValue::S(first_seen.to_rfc3339()))
+                .item("last_seen", AttributeValue::S(last_seen.to_rfc3339()))
+                .send()
+                .await
+                .map_err(from_sdk)?;
+        }
+
+        Ok(migrated)
+    }
+}
+
+/// Sort key of a catalog item, `node#<node_id>` or `dimension#<dimension>`
+fn catalog_entry(kind: &str, name: &str) -> String {
+    format!("{}#{}", kind, name)
+}
+
+/// The catalog item a record widens, its dimension or else its node
+fn catalog_name(record: &TDigestRecord) -> (&'static str, &str) {
+    match &record.dimension {
+        Some(dimension) => (CATALOG_DIMENSION, dimension),
+        None => (CATALOG_NODE, &record.node_id),
+    }
+}
+
+/// Partition key of the series index. Each dimension gets its own series, `_`
+/// for node-wide records, so a query never reads items of other dimensions.
+/// The node is left out: most queries span every node of an app.
+fn series_key(app: &str, agg_level: &str, dimension: Option<&str>, metric: &str) -> String {
+    format!(
+        "{}#{}#{}#{}",
+        app,
+        agg_level,
+        dimension.unwrap_or("_"),
+        metric
+    )
+}
+
+fn record_series_key(record: &TDigestRecord) -> String {
+    series_key(
+        &record.app,
+        &record.agg_level,
+        record.dimension.as_deref(),
+        &record.metric,
+    )
+}
+
+/// Converts a TDigestRecord into a HashMap of AttributeValues ready for DynamoDB
+fn record_to_item(record: &TDigestRecord) -> Result<HashMap<String, AttributeValue>> {
+    let mut item = HashMap::new();
+
+    item.insert("key".to_string(), AttributeValue::S(record.key.clone()));
+    item.insert(
+        "series".to_string(),
+        AttributeValue::S(record_series_key(record)),
+    );
+    item.insert("app".to_string(), AttributeValue::S(record.app.clone()));
+    item.insert(
+        "agg_level".to_string(),
+        AttributeValue::S(record.agg_level.clone()),
+    );
+    item.insert(
+        "created_at".to_string(),
+        AttributeValue::S(record.created_at.to_rfc3339()),
+    );
+    item.insert(
+        "node_id".to_string(),
+        AttributeValue::S(record.node_id.clone()),
+    );
+    if let Some(dimension) = &record.dimension {
+        item.insert(
+            "dimension".to_string(),
+            AttributeValue::S(dimension.clone()),
+        );
+    }
+    item.insert(
+        "count".to_string(),
+        AttributeValue::N(record.count.to_string()),
+    );
+    if record.partial {
+        item.insert("partial".to_string(), AttributeValue::Bool(true));
+    }
+    if record.dropped > 0 {
+        item.insert(
+            "dropped".to_string(),
+            AttributeValue::N(record.dropped.to_string()),
+        );
+    }
+
+    if record.metric != metric::SRTT {
+        item.insert(
+            "metric".to_string(),
+            AttributeValue::S(record.metric.clone()),
+        );
+    }
+
+    let digest_json = serde_json::to_string(&record.tdigest)?;
+    item.insert("tdigest".to_string(), AttributeValue::S(digest_json));
+
+    Ok(item)
+}
+
+/// Converts a DynamoDB item back into a TDigestRecord
+fn item_to_record(item: &HashMap<String, AttributeValue>) -> Result<TDigestRecord> {
+    let tdigest = serde_json::from_str::<TDigest>(&string_attr(item, "tdigest")?)?;
+    let created_at = parse_created_at(item)?;
+    // items written before sample counts were recorded only have the digest's count
+    let count = match item.get("count") {
+        Some(AttributeValue::N(n)) => n
+            .parse()
+            .map_err(|e| Error::SchemaMismatch(format!("invalid count {:?}: {}", n, e)))?,
+        _ => tdigest.count() as u64,
+    };
+    let dropped = match item.get("dropped") {
+        Some(AttributeValue::N(n)) => n
+            .parse()
+            .map_err(|e| Error::SchemaMismatch(format!("invalid dropped {:?}: {}", n, e)))?,
+        _ => 0,
+    };
+
+    Ok(TDigestRecord {
+        key: string_attr(item, "key")?,
+        app: string_attr(item, "app")?,
+        agg_level: string_attr(item, "agg_level")?,
+        created_at,
+        node_id: string_attr(item, "node_id")?,
+        dimension: string_attr(item, "dimension").ok(),
+        count,
+        tdigest,
+        partial: matches!(item.get("partial"), Some(AttributeValue::Bool(true))),
+        dropped,
+        metric: string_attr(item, "metric").unwrap_or_else(|_| metric::SRTT.to_string()),
+    })
+}
+
+fn string_attr(item: &HashMap<String, AttributeValue>, name: &str) -> Result<String> {
+    match item.get(name) {
+        Some(AttributeValue::S(v)) => Ok(v.clone()),
+        _ => Err(Error::SchemaMismatch(format!(
+            "item is missing string attribute {}",
+            name
+        ))),
+    }
+}
+
+fn parse_created_at(item: &HashMap<String, AttributeValue>) -> Result<DateTime<Utc>> {
+    time_attr(item, "created_at")
+}
+
+fn time_attr(item: &HashMap<String, AttributeValue>, name: &str) -> Result<DateTime<Utc>> {
+    let value = string_attr(item, name)?;
+
+    DateTime::parse_from_rfc3339(&value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| Error::SchemaMismatch(format!("invalid {} {:?}: {}", name, value, e)))
+}
+
+/// Group items by the `name` attribute into listings spanning the earliest
+/// `first` and latest `last` attribute, sorted by name. Items without a name
+/// are skipped.
+fn listings(
+    items: &[HashMap<String, AttributeValue>],
+    name: &str,
+    first: &str,
+    last: &str,
+) -> Result<Vec<Listing>> {
+    let mut seen: BTreeMap<String, (DateTime<Utc>, DateTime<Utc>)> = BTreeMap::new();
+    for item in items {
+        let Ok(name) = string_attr(item, name) else {
+            continue;
+        };
+        let (item_first, item_last) = (time_attr(item, first)?, time_attr(item, last)?);
+
+        let (first_seen, last_seen) = seen.entry(name).or_insert((item_first, item_last));
+        *first_seen = (*first_seen).min(item_first);
+        *last_seen = (*last_seen).max(item_last);
+    }
+
+    Ok(seen
+        .into_iter()
+        .map(|(name, (first_seen, last_seen))| Listing {
+            name,
+            first_seen,
+            last_seen,
+        })
+        .collect())
+}
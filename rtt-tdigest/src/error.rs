@@ -0,0 +1,154 @@
+use aws_sdk_dynamodb::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use std::fmt::Debug;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors returned by the digest stores and `Service`
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A digest could not be serialized or a stored digest could not be read back
+    #[error("digest serialization failed: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    /// The backend could not be reached, retrying later may succeed
+    #[error("digest store unavailable: {0}")]
+    Unavailable(#[source] BoxError),
+
+    /// The backend rejected the request due to load, retry with backoff
+    #[error("digest store throttled the request: {0}")]
+    Throttled(#[source] BoxError),
+
+    /// A table, index or database the store relies on does not exist
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    /// Stored data does not have the shape this version expects
+    #[error("schema mismatch: {0}")]
+    SchemaMismatch(String),
+
+    /// The store configuration is invalid
+    #[error("invalid store configuration: {0}")]
+    Config(String),
+
+    /// Any other backend failure
+    #[error("digest store failed: {0}")]
+    Backend(#[source] BoxError),
+}
+
+impl Error {
+    /// Whether retrying the same operation later may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Error::Unavailable(_) | Error::Throttled(_))
+    }
+}
+
+/// Classify a DynamoDB SDK failure
+pub(crate) fn from_sdk<E, R>(err: SdkError<E, R>) -> Error
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+    R: Debug + Send + Sync + 'static,
+{
+    match &err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            return Error::Unavailable(Box::new(err));
+        }
+        _ => {}
+    }
+
+    match err.code() {
+        Some(
+            "ProvisionedThroughputExceededException"
+            | "RequestLimitExceeded"
+            | "ThrottlingException",
+        ) => Error::Throttled(Box::new(err)),
+        Some("ResourceNotFoundException") => Error::NotFound(DisplayErrorContext(&err).to_string()),
+        // raised for a missing index or mismatched key attributes
+        Some("ValidationException") => Error::SchemaMismatch(DisplayErrorContext(&err).to_string()),
+        _ => Error::Backend(Box::new(err)),
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        use rusqlite::ErrorCode;
+
+        match &err {
+            rusqlite::Error::SqliteFailure(e, message) => match e.code {
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => {
+                    Error::Throttled(Box::new(err))
+                }
+                ErrorCode::CannotOpen | ErrorCode::NotADatabase | ErrorCode::PermissionDenied => {
+                    Error::Unavailable(Box::new(err))
+                }
+                _ if message.as_deref().is_some_and(|m| m.starts_with("no such")) => {
+                    Error::SchemaMismatch(err.to_string())
+                }
+                _ => Error::Backend(Box::new(err)),
+            },
+            rusqlite::Error::InvalidColumnType(..)
+            | rusqlite::Error::InvalidColumnIndex(_)
+            | rusqlite::Error::InvalidColumnName(_)
+            | rusqlite::Error::FromSqlConversionFailure(..) => {
+                Error::SchemaMismatch(err.to_string())
+            }
+            _ => Error::Backend(Box::new(err)),
+        }
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(err: tokio::task::JoinError) -> Self {
+        Error::Backend(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::{Connection, ffi};
+
+    fn sqlite_failure(code: i32) -> Error {
+        rusqlite::Error::SqliteFailure(ffi::Error::new(code), None).into()
+    }
+
+    #[test]
+    fn only_unavailable_and_throttled_are_retryable() {
+        let boxed = || -> BoxError { "failed".into() };
+
+        assert!(Error::Unavailable(boxed()).is_retryable());
+        assert!(Error::Throttled(boxed()).is_retryable());
+
+        assert!(!Error::Backend(boxed()).is_retryable());
+        assert!(!Error::NotFound("table".to_string()).is_retryable());
+        assert!(!Error::SchemaMismatch("column".to_string()).is_retryable());
+        assert!(!Error::Config("backend".to_string()).is_retryable());
+        let json = serde_json::from_str::<u32>("x").unwrap_err();
+        assert!(!Error::Serialization(json).is_retryable());
+    }
+
+    #[test]
+    fn sqlite_errors_are_classified() {
+        assert!(matches!(
+            sqlite_failure(ffi::SQLITE_BUSY),
+            Error::Throttled(_)
+        ));
+        assert!(matches!(
+            sqlite_failure(ffi::SQLITE_LOCKED),
+            Error::Throttled(_)
+        ));
+        assert!(matches!(
+            sqlite_failure(ffi::SQLITE_CANTOPEN),
+            Error::Unavailable(_)
+        ));
+        assert!(matches!(
+            sqlite_failure(ffi::SQLITE_CONSTRAINT),
+            Error::Backend(_)
+        ));
+
+        let conn = Connection::open_in_memory().unwrap();
+        let missing: Error = conn.execute("DELETE FROM missing", []).unwrap_err().into();
+        assert!(matches!(missing, Error::SchemaMismatch(_)), "{missing:?}");
+    }
+}
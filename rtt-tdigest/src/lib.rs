@@ -1,6 +1,18 @@
+mod config;
+mod dynamo;
+mod error;
+pub mod metric;
 mod record;
 mod service;
+mod sqlite;
+mod store;
 mod summary;
 
+pub use config::StoreConfig;
+pub use dynamo::DynamoStore;
+pub use error::{BoxError, Error, Result};
+pub use record::TDigestRecord;
 pub use service::Service;
+pub use sqlite::SqliteStore;
+pub use store::{DigestQuery, DigestStore, ListBy, Listing, MAX_QUERY_NODES};
 pub use summary::Summary;
@@ -0,0 +1,30 @@
+//! Names of the metrics digests are stored for. Digests of the same app,
+//! aggregation level and window are kept apart per metric.
+
+/// Smoothed round trip time, the metric of records that name none
+pub const SRTT: &str = "srtt";
+/// Mean deviation of the round trip time
+pub const MDEV: &str = "mdev";
+/// Minimum round trip time over the kernel's recent window
+pub const RTT_MIN: &str = "rtt_min";
+/// Congestion window
+pub const SND_CWND: &str = "snd_cwnd";
+/// Segments retransmitted over the connection's lifetime, each sample is the
+/// connection's running total so the digest describes how many retransmits
+/// sampled connections have built up, not a retransmit rate
+pub const TOTAL_RETRANS: &str = "total_retrans";
+/// Segments sent and not yet acknowledged
+pub const PACKETS_OUT: &str = "packets_out";
+/// Most recent delivery rate sample
+pub const DELIVERY_RATE: &str = "delivery_rate";
+
+/// Unit of the values in a metric's digests, `None` for unknown metrics
+pub fn unit(metric: &str) -> Option<&'static str> {
+    match metric {
+        SRTT | MDEV | RTT_MIN => Some("ms"),
+        SND_CWND | PACKETS_OUT => Some("packets"),
+        TOTAL_RETRANS => Some("segments"),
+        DELIVERY_RATE => Some("Mbit/s"),
+        _ => None,
+    }
+}
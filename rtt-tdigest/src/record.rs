@@ -1,13 +1,217 @@
+use crate::metric;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tdigest::TDigest;
 
-#[derive(Serialize, Deserialize)]
-pub(crate) struct TDigestRecord {
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TDigestRecord {
     pub key: String,
     pub app: String,
     pub agg_level: String,
     pub created_at: DateTime<Utc>,
     pub node_id: String,
+    pub dimension: Option<String>,
+    pub count: u64,
     pub tdigest: TDigest,
+    /// Set when the window was cut short, e.g. by the collector shutting down.
+    /// Partial records are keyed apart from the record a restarted collector
+    /// stores for the rest of the window, see `expected_key`.
+    #[serde(default)]
+    pub partial: bool,
+    /// Samples the collector lost during the window, e.g. because its ring
+    /// buffer was full. Only set on node-wide records.
+    #[serde(default)]
+    pub dropped: u64,
+    /// Metric the digest's samples are of, see `metric`
+    #[serde(default = "default_metric")]
+    pub metric: String,
+}
+
+fn default_metric() -> String {
+    metric::SRTT.to_string()
+}
+
+impl TDigestRecord {
+    /// A complete `metric::SRTT` record keyed by `key_for`, see `with_metric`
+    pub fn new(
+        app: &str,
+        agg_level: &str,
+        node_id: &str,
+        created_at: DateTime<Utc>,
+        dimension: Option<&str>,
+        tdigest: TDigest,
+        count: u64,
+    ) -> Self {
+        Self {
+            key: Self::key_for(app, agg_level, node_id, created_at, dimension, metric::SRTT),
+            app: app.to_string(),
+            agg_level: agg_level.to_string(),
+            created_at,
+            node_id: node_id.to_string(),
+            dimension: dimension.map(str::to_string),
+            count,
+            tdigest,
+            partial: false,
+            dropped: 0,
+            metric: metric::SRTT.to_string(),
+        }
+    }
+
+    /// Key unique to the app, aggregation level, node, window start,
+    /// dimension and metric of a record:
+    /// `app:agg_level:node:created_at[:dimension][#metric]`, the metric is left
+    /// out for `metric::SRTT`
+    pub fn key_for(
+        app: &str,
+        agg_level: &str,
+        node_id: &str,
+        created_at: DateTime<Utc>,
+        dimension: Option<&str>,
+        metric: &str,
+    ) -> String {
+        let key = format!(
+            "{}:{}:{}:{}",
+            app,
+            agg_level,
+            node_id,
+            created_at.to_rfc3339()
+        );
+        let key = match dimension {
+            Some(dimension) => format!("{}:{}", key, dimension),
+            None => key,
+        };
+        match metric {
+            metric::SRTT => key,
+            metric => format!("{}#{}", key, metric),
+        }
+    }
+
+    /// The same record for another metric, rekeyed accordingly
+    pub fn with_metric(mut self, metric: &str) -> Self {
+        self.metric = metric.to_string();
+        self.key = self.expected_key();
+        self
+    }
+
+    /// The same record, cut short or not, rekeyed accordingly
+    pub fn with_partial(mut self, partial: bool) -> Self {
+        self.partial = partial;
+        self.key = self.expected_key();
+        self
+    }
+
+    /// The key this record should be stored under, differs from `key` for
+    /// records written with an older key scheme. Partial records get a
+    /// `#partial` suffix.
+    pub fn expected_key(&self) -> String {
+        let key = Self::key_for(
+            &self.app,
+            &self.agg_level,
+            &self.node_id,
+            self.created_at,
+            self.dimension.as_deref(),
+            &self.metric,
+        );
+        match self.partial {
+            true => format!("{}#partial", key),
+            false => key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn window() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 6, 1, 12, 30, 0).unwrap()
+    }
+
+    #[test]
+    fn key_for_node_wide_srtt() {
+        assert_eq!(
+            TDigestRecord::key_for("app", "1m", "node", window(), None, metric::SRTT),
+            "app:1m:node:2023-06-01T12:30:00+00:00"
+        );
+    }
+
+    #[test]
+    fn key_for_appends_dimension_and_metric() {
+        assert_eq!(
+            TDigestRecord::key_for(
+                "app",
+                "5m",
+                "node",
+                window(),
+                Some("10.0.0.1:443"),
+                metric::SRTT
+            ),
+            "app:5m:node:2023-06-01T12:30:00+00:00:10.0.0.1:443"
+        );
+        assert_eq!(
+            TDigestRecord::key_for("app", "1m", "node", window(), None, metric::SND_CWND),
+            "app:1m:node:2023-06-01T12:30:00+00:00#snd_cwnd"
+        );
+        assert_eq!(
+            TDigestRecord::key_for(
+                "app",
+                "1m",
+                "node",
+                window(),
+                Some("[::1]:80"),
+                metric::MDEV
+            ),
+            "app:1m:node:2023-06-01T12:30:00+00:00:[::1]:80#mdev"
+        );
+    }
+
+    #[test]
+    fn with_metric_rekeys() {
+        let record = TDigestRecord {
+            key: "app:1m:node".to_string(),
+            ..TDigestRecord::new("app", "1m", "node", window(), None, TDigest::default(), 0)
+        };
+        assert_eq!(
+            record.expected_key(),
+            "app:1m:node:2023-06-01T12:30:00+00:00"
+        );
+
+        let record = record.with_metric(metric::RTT_MIN);
+        assert_eq!(record.metric, metric::RTT_MIN);
+        assert_eq!(record.key, "app:1m:node:2023-06-01T12:30:00+00:00#rtt_min");
+        assert_eq!(record.key, record.expected_key());
+    }
+
+    #[test]
+    fn partial_records_are_keyed_apart() {
+        let record = TDigestRecord::new("app", "1m", "node", window(), None, TDigest::default(), 0);
+
+        let partial = record.clone().with_partial(true);
+        assert!(partial.partial);
+        assert_eq!(partial.key, format!("{}#partial", record.key));
+        assert_eq!(partial.key, partial.expected_key());
+        assert_eq!(
+            partial.with_metric(metric::MDEV).key,
+            "app:1m:node:2023-06-01T12:30:00+00:00#mdev#partial"
+        );
+    }
+
+    #[test]
+    fn metric_defaults_to_srtt_when_missing() {
+        let json = serde_json::json!({
+            "key": "app:1m:node",
+            "app": "app",
+            "agg_level": "1m",
+            "created_at": "2023-06-01T12:30:00Z",
+            "node_id": "node",
+            "dimension": null,
+            "count": 1,
+            "tdigest": TDigest::new_with_size(100).merge_unsorted(vec![1.0]),
+        });
+
+        let record: TDigestRecord = serde_json::from_value(json).unwrap();
+        assert_eq!(record.metric, metric::SRTT);
+        assert!(!record.partial);
+    }
 }
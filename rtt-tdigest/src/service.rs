@@ -1,157 +1,109 @@
+use crate::error::Result;
 use crate::record::TDigestRecord;
-use anyhow::Result;
-use aws_sdk_dynamodb::Client;
-use aws_sdk_dynamodb::types::AttributeValue;
-use chrono;
+use crate::store::{DigestQuery, DigestStore, ListBy, Listing};
 use chrono::{DateTime, Duration, DurationRound, Utc};
-use serde_json::{self, ser};
-use std::collections::HashMap;
+use std::sync::Arc;
 use tdigest::TDigest;
 
-const TABLE_NAME: &str = "rtt-tdigests";
-
 #[derive(Clone)]
 pub struct Service {
-    client: Client,
+    store: Arc<dyn DigestStore>,
     app: String,
     node: String,
 }
 
 impl Service {
-    pub fn new(client: Client, app: String, node: String) -> Self {
-        Self { client, app, node }
+    pub fn new(store: Arc<dyn DigestStore>, app: String, node: String) -> Self {
+        Self { store, app, node }
+    }
+
+    /// Store the digest of `count` samples collected in the window starting at
+    /// `window_start`, for the node or for one dimension of it (e.g. a
+    /// destination) when `dimension` is set
+    pub async fn store_tdigest(
+        &self,
+        agg_level: String,
+        window_start: DateTime<Utc>,
+        dimension: Option<String>,
+        tdigest: TDigest,
+        count: u64,
+    ) -> Result<()> {
+        let record = self.record(agg_level, window_start, dimension, tdigest, count);
+
+        self.store_record(&record).await
     }
 
-    pub async fn store_tdigest(&self, agg_level: String, tdigest: TDigest) -> Result<()> {
-        let now = chrono::Utc::now();
-        let created_at = now
+    /// Build the record `store_tdigest` would write, so callers can keep it
+    /// around (e.g. to retry later) before storing it with `store_record`.
+    /// Records are of `metric::SRTT`, see `TDigestRecord::with_metric`.
+    pub fn record(
+        &self,
+        agg_level: String,
+        window_start: DateTime<Utc>,
+        dimension: Option<String>,
+        tdigest: TDigest,
+        count: u64,
+    ) -> TDigestRecord {
+        let created_at = window_start
             .duration_trunc(Duration::minutes(1))
-            .unwrap_or_else(|_| now);
+            .unwrap_or(window_start);
 
-        let record = TDigestRecord {
-            key: self.record_key(agg_level.clone()),
-            app: self.app.clone(),
-            agg_level,
+        TDigestRecord::new(
+            &self.app,
+            &agg_level,
+            &self.node,
             created_at,
-            node_id: self.node.clone(),
+            dimension.as_deref(),
             tdigest,
-        };
-
-        let dynanmo_hashmap = record_to_item(&record)?;
+            count,
+        )
+    }
 
-        match self
-            .client
-            .put_item()
-            .table_name(TABLE_NAME)
-            .set_item(Some(dynanmo_hashmap))
-            .send()
-            .await
-        {
-            Ok(_) => {
-                println!("Successfully stored digest for {}/{}", self.app, self.node);
-                Ok(())
-            }
-            Err(e) => {
-                eprintln!("Failed to store digest: {}", e);
-                Err(anyhow::anyhow!("DynamoDB storage failed: {}", e))
-            }
-        }
+    /// Store a record built by `record`. Records are keyed by their window, so
+    /// storing the same record twice overwrites it.
+    pub async fn store_record(&self, record: &TDigestRecord) -> Result<()> {
+        self.store.store(record).await
     }
 
-    pub async fn query_digests(
-        &self,
-        agg_level: &str,
-        from: DateTime<Utc>,
-        to: DateTime<Utc>,
-    ) -> Result<Vec<TDigest>> {
-        let from_str = from.to_rfc3339();
-        let to_str = to.to_rfc3339();
+    /// App this service stores digests for
+    pub fn app(&self) -> &str {
+        &self.app
+    }
 
-        // prepare expression attribute values
-        let mut expr_values = HashMap::new();
-        expr_values.insert(":app".to_string(), AttributeValue::S(self.app.clone()));
-        expr_values.insert(
-            ":agg_level".to_string(),
-            AttributeValue::S(agg_level.to_string()),
-        );
-        expr_values.insert(":from".to_string(), AttributeValue::S(from_str));
-        expr_values.insert(":to".to_string(), AttributeValue::S(to_str));
+    /// Query digest records matching the query. Without a `dimension` only
+    /// node-wide digests are returned.
+    pub async fn query_digests(&self, query: &DigestQuery) -> Result<Vec<TDigestRecord>> {
+        let records = self.store.query(query).await?;
+        println!("found {} digests to merge", records.len());
 
-        // prepare expression attribute names
-        let mut expr_names = HashMap::new();
-        expr_names.insert("#app".to_string(), "app".to_string());
-        expr_names.insert("#agg_level".to_string(), "agg_level".to_string());
-        expr_names.insert("#created_at".to_string(), "created_at".to_string());
+        Ok(records)
+    }
 
-        // form the filter expression
-        let filter_expr =
-            "#app = :app AND #agg_level = :agg_level AND #created_at BETWEEN :from AND :to"
-                .to_string();
+    /// Apps with stored digests
+    pub async fn list_apps(&self) -> Result<Vec<Listing>> {
+        self.store.list(&ListBy::Apps).await
+    }
 
-        let scan_output = match self
-            .client
-            .scan()
-            .table_name(TABLE_NAME)
-            .filter_expression(filter_expr)
-            .set_expression_attribute_names(Some(expr_names))
-            .set_expression_attribute_values(Some(expr_values))
-            .send()
-            .await
-        {
-            Ok(output) => output,
-            Err(err) => {
-                eprintln!("DynamoDB error details: {:?}", err);
-                return Err(err.into());
-            }
-        };
+    /// Whether the app has stored any digests
+    pub async fn app_exists(&self, app: &str) -> Result<bool> {
+        self.store.app_exists(app).await
+    }
 
-        // get and deserialize from results
-        let tdigests = scan_output
-            .items
-            .unwrap_or_default()
-            .iter()
-            .filter_map(|item| match item.get("tdigest") {
-                Some(AttributeValue::S(v)) => Some(v),
-                _ => None,
-            })
-            .map(|tdigest| {
-                serde_json::from_str::<TDigest>(tdigest)
-                    .map_err(|e| anyhow::anyhow!("Failed to deserialize TDigest: {}", e))
+    /// Nodes that stored digests for the app
+    pub async fn list_nodes(&self, app: &str) -> Result<Vec<Listing>> {
+        self.store
+            .list(&ListBy::Nodes {
+                app: app.to_string(),
             })
-            .collect::<Result<Vec<TDigest>>>()?;
-
-        println!("found {} digests to merge", tdigests.len());
-
-        Ok(tdigests)
+            .await
     }
 
-    fn record_key(&self, agg_level: String) -> String {
-        format!("{}:{}:{}", self.app, agg_level, self.node)
+    /// Dimensions (e.g. destinations) stored for the app
+    pub async fn list_dimensions(&self, app: &str) -> Result<Vec<Listing>> {
+        self.store
+            .list(&ListBy::Dimensions {
+                app: app.to_string(),
+            })
+            .await
     }
 }
-
-/// Converts a TDigestRecord into a HashMap of AttributeValues ready for DynamoDB
-fn record_to_item(record: &TDigestRecord) -> Result<HashMap<String, AttributeValue>> {
-    let mut item = HashMap::new();
-
-    item.insert("key".to_string(), AttributeValue::S(record.key.clone()));
-    item.insert("app".to_string(), AttributeValue::S(record.app.clone()));
-    item.insert(
-        "agg_level".to_string(),
-        AttributeValue::S(record.agg_level.clone()),
-    );
-    item.insert(
-        "created_at".to_string(),
-        AttributeValue::S(record.created_at.to_rfc3339()),
-    );
-    item.insert(
-        "node_id".to_string(),
-        AttributeValue::S(record.node_id.clone()),
-    );
-
-    let digest_json = serde_json::to_string(&record.tdigest.clone())
-        .map_err(|e| anyhow::anyhow!("Failed to serialize TDigest to JSON: {}", e))?;
-    item.insert("tdigest".to_string(), AttributeValue::S(digest_json));
-
-    Ok(item)
-}
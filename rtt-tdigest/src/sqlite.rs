@@ -0,0 +1,504 @@
+use crate::error::{Error, Result};
+use crate::record::TDigestRecord;
+use crate::store::{DigestQuery, DigestStore, ListBy, Listing};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, Row, params};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tdigest::TDigest;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tdigests (
+    key TEXT PRIMARY KEY,
+    app TEXT NOT NULL,
+    agg_level TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    node_id TEXT NOT NULL,
+    dimension TEXT,
+    count INTEGER NOT NULL,
+    tdigest BLOB NOT NULL,
+    partial INTEGER NOT NULL DEFAULT 0,
+    dropped INTEGER NOT NULL DEFAULT 0,
+    metric TEXT NOT NULL DEFAULT 'srtt'
+);
+CREATE INDEX IF NOT EXISTS tdigests_app_agg_level_created_at
+    ON tdigests (app, agg_level, created_at);
+";
+
+/// Columns added to `tdigests` after its first release, with their definitions
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("partial", "INTEGER NOT NULL DEFAULT 0"),
+    ("dropped", "INTEGER NOT NULL DEFAULT 0"),
+    ("metric", "TEXT NOT NULL DEFAULT 'srtt'"),
+];
+
+/// Stores digest records in a local SQLite database, `created_at` is kept as
+/// unix seconds so time range queries can use the index
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database at `path` and ensure the schema exists
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        // databases created by older releases lack the columns added since
+        for (column, definition) in ADDED_COLUMNS {
+            let exists: bool = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM pragma_table_info('tdigests') WHERE name = ?1)",
+                [column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                conn.execute(
+                    &format!("ALTER TABLE tdigests ADD COLUMN {column} {definition}"),
+                    [],
+                )?;
+            }
+        }
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run `f` against the connection on the blocking thread pool
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().map_err(|e| {
+                Error::Backend(format!("SQLite connection lock poisoned: {}", e).into())
+            })?;
+            f(&conn)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl DigestStore for SqliteStore {
+    async fn store(&self, record: &TDigestRecord) -> Result<()> {
+        let record = record.clone();
+        self.with_conn(move |conn| insert(conn, &record)).await
+    }
+
+    async fn query(&self, query: &DigestQuery) -> Result<Vec<TDigestRecord>> {
+        let query = query.clone();
+        // nodes are passed as a JSON array so any number of them binds to one parameter
+        let nodes = match query.nodes.is_empty() {
+            true => None,
+            false => Some(serde_json::to_string(&query.nodes)?),
+        };
+
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT key, app, agg_level, created_at, node_id, dimension, count, tdigest,
+                        partial, dropped, metric
+                 FROM tdigests
+                 WHERE app = ?1 AND agg_level = ?2 AND created_at BETWEEN ?3 AND ?4
+                   AND (?5 IS NULL OR node_id IN (SELECT value FROM json_each(?5)))
+                   AND dimension IS ?6 AND metric = ?7
+                 ORDER BY created_at",
+            )?;
+            let mut rows = stmt.query(params![
+                query.app,
+                query.agg_level,
+                query.from.timestamp(),
+                query.to.timestamp(),
+                nodes,
+                query.dimension,
+                query.metric,
+            ])?;
+
+            let mut records = Vec::new();
+            while let Some(row) = rows.next()? {
+                records.push(row_to_record(row)?);
+            }
+
+            Ok(records)
+        })
+        .await
+    }
+
+    async fn list(&self, by: &ListBy) -> Result<Vec<Listing>> {
+        let (column, app) = match by {
+            ListBy::Apps => ("app", None),
+            ListBy::Nodes { app } => ("node_id", Some(app.clone())),
+            ListBy::Dimensions { app } => ("dimension", Some(app.clone())),
+        };
+
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT {column}, MIN(created_at), MAX(created_at)
+                 FROM tdigests
+                 WHERE (?1 IS NULL OR app = ?1) AND {column} IS NOT NULL
+                 GROUP BY {column}
+                 ORDER BY {column}"
+            ))?;
+            let mut rows = stmt.query(params![app])?;
+
+            let mut listings = Vec::new();
+            while let Some(row) = rows.next()? {
+                listings.push(Listing {
+                    name: row.get(0)?,
+                    first_seen: timestamp(row.get(1)?)?,
+                    last_seen: timestamp(row.get(2)?)?,
+                });
+            }
+
+            Ok(listings)
+        })
+        .await
+    }
+
+    async fn app_exists(&self, app: &str) -> Result<bool> {
+        let app = app.to_string();
+
+        self.with_conn(move |conn| {
+            // served by the index that leads with app
+            let exists = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM tdigests WHERE app = ?1)",
+                params![app],
+                |row| row.get(0),
+            )?;
+            Ok(exists)
+        })
+        .await
+    }
+
+    async fn migrate_keys(&self) -> Result<usize> {
+        self.with_conn(|conn| {
+            let tx = conn.unchecked_transaction()?;
+
+            let mut records = Vec::new();
+            {
+                let mut stmt = tx.prepare(
+                    "SELECT key, app, agg_level, created_at, node_id, dimension, count, tdigest,
+                        partial, dropped, metric
+                     FROM tdigests",
+                )?;
+                let mut rows = stmt.query([])?;
+                while let Some(row) = rows.next()? {
+                    records.push(row_to_record(row)?);
+                }
+            }
+
+            let mut migrated = 0;
+            for record in records {
+                let key = record.expected_key();
+                if key != record.key {
+                    // a row already stored under the new key was written by a newer
+                    // release and is kept, the outdated row is dropped either way
+                    let old_key = record.key.clone();
+                    insert_new(&tx, &TDigestRecord { key, ..record })?;
+                    tx.execute("DELETE FROM tdigests WHERE key = ?1", params![old_key])?;
+                    migrated += 1;
+                }
+            }
+
+            tx.commit()?;
+            Ok(migrated)
+        })
+        .await
+    }
+}
+
+/// Inserts a record, replacing any row with the same key
+fn insert(conn: &Connection, record: &TDigestRecord) -> Result<()> {
+    write(conn, "INSERT OR REPLACE", record)
+}
+
+/// Inserts a record unless a row with the same key exists
+fn insert_new(conn: &Connection, record: &TDigestRecord) -> Result<()> {
+    write(conn, "INSERT OR IGNORE", record)
+}
+
+fn write(conn: &Connection, insert: &str, record: &TDigestRecord) -> Result<()> {
+    let digest_json = serde_json::to_vec(&record.tdigest)?;
+    conn.execute(
+        &format!(
+            "{} INTO tdigests
+                (key, app, agg_level, created_at, node_id, dimension, count, tdigest, partial,
+                 dropped, metric)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            insert
+        ),
+        params![
+            record.key,
+            record.app,
+            record.agg_level,
+            record.created_at.timestamp(),
+            record.node_id,
+            record.dimension,
+            record.count as i64,
+            digest_json,
+            record.partial,
+            record.dropped as i64,
+            record.metric,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Converts a `tdigests` row into a TDigestRecord
+fn row_to_record(row: &Row<'_>) -> Result<TDigestRecord> {
+    let created_at: i64 = row.get(3)?;
+    let count: i64 = row.get(6)?;
+    let digest_json: Vec<u8> = row.get(7)?;
+
+    let created_at = timestamp(created_at)?;
+    let tdigest = serde_json::from_slice::<TDigest>(&digest_json)?;
+
+    Ok(TDigestRecord {
+        key: row.get(0)?,
+        app: row.get(1)?,
+        agg_level: row.get(2)?,
+        created_at,
+        node_id: row.get(4)?,
+        dimension: row.get(5)?,
+        count: count as u64,
+        tdigest,
+        partial: row.get(8)?,
+        dropped: row.get::<_, i64>(9)? as u64,
+        metric: row.get(10)?,
+    })
+}
+
+/// Converts a stored `created_at` back into a timestamp
+fn timestamp(secs: i64) -> Result<DateTime<Utc>> {
+    DateTime::from_timestamp(secs, 0)
+        .ok_or_else(|| Error::SchemaMismatch(format!("created_at out of range: {}", secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric;
+    use chrono::TimeZone;
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 6, 1, 0, minute, 0).unwrap()
+    }
+
+    fn record(
+        node_id: &str,
+        minute: u32,
+        dimension: Option<&str>,
+        metric: &str,
+        values: &[f64],
+    ) -> TDigestRecord {
+        let tdigest = TDigest::new_with_size(100).merge_unsorted(values.to_vec());
+        let record = TDigestRecord::new(
+            "app",
+            "1m",
+            node_id,
+            at(minute),
+            dimension,
+            tdigest,
+            values.len() as u64,
+        );
+
+        TDigestRecord {
+            dropped: u64::from(minute),
+            ..record.with_metric(metric)
+        }
+        .with_partial(minute == 0)
+    }
+
+    fn query() -> DigestQuery {
+        DigestQuery {
+            app: "app".to_string(),
+            agg_level: "1m".to_string(),
+            metric: metric::SRTT.to_string(),
+            nodes: Vec::new(),
+            dimension: None,
+            from: at(0),
+            to: at(59),
+        }
+    }
+
+    async fn store_all(store: &SqliteStore, records: &[TDigestRecord]) {
+        for record in records {
+            store.store(record).await.unwrap();
+        }
+    }
+
+    fn keys(records: &[TDigestRecord]) -> Vec<&str> {
+        records.iter().map(|r| r.key.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn records_round_trip() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let stored = record("a", 0, None, metric::SRTT, &[1.0, 2.0, 3.0]);
+        store.store(&stored).await.unwrap();
+
+        let found = store.query(&query()).await.unwrap();
+        assert_eq!(keys(&found), [&stored.key]);
+        let found = &found[0];
+        assert_eq!(found.created_at, stored.created_at);
+        assert_eq!(found.node_id, "a");
+        assert_eq!(found.dimension, None);
+        assert_eq!(found.count, 3);
+        assert_eq!(found.tdigest.count(), 3.0);
+        assert_eq!(found.tdigest.max(), 3.0);
+        assert!(found.partial);
+        assert_eq!(found.metric, metric::SRTT);
+
+        // storing under the same key replaces the record
+        store
+            .store(&record("a", 0, None, metric::SRTT, &[4.0]))
+            .await
+            .unwrap();
+        let found = store.query(&query()).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].count, 1);
+    }
+
+    #[tokio::test]
+    async fn migrate_keys_rewrites_outdated_keys() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let current = record("a", 1, None, metric::SRTT, &[1.0]);
+        let outdated = TDigestRecord {
+            key: "app:1m:b".to_string(),
+            ..record("b", 2, None, metric::SRTT, &[1.0])
+        };
+        // an outdated copy of a record also stored under its current key, the
+        // current record is kept
+        let conflicting = TDigestRecord {
+            key: "app:1m:a".to_string(),
+            ..record("a", 1, None, metric::SRTT, &[1.0, 2.0])
+        };
+        store_all(&store, &[current.clone(), outdated, conflicting]).await;
+
+        assert_eq!(store.migrate_keys().await.unwrap(), 2);
+        assert_eq!(store.migrate_keys().await.unwrap(), 0);
+
+        let found = store.query(&query()).await.unwrap();
+        assert_eq!(found.len(), 2);
+        for record in &found {
+            assert_eq!(record.key, record.expected_key());
+        }
+        assert_eq!(found[0].key, current.key);
+        assert_eq!(found[0].count, 1);
+    }
+
+    #[tokio::test]
+    async fn queries_filter_by_range_node_and_dimension() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let a = record("a", 1, None, metric::SRTT, &[1.0]);
+        let b = record("b", 2, None, metric::SRTT, &[1.0]);
+        let c = record("c", 3, None, metric::SRTT, &[1.0]);
+        let late = record("a", 59, None, metric::SRTT, &[1.0]);
+        let dimension = record("a", 1, Some("10.0.0.1:443"), metric::SRTT, &[1.0]);
+        store_all(
+            &store,
+            &[
+                a.clone(),
+                b.clone(),
+                c.clone(),
+                late.clone(),
+                dimension.clone(),
+            ],
+        )
+        .await;
+
+        let found = store.query(&query()).await.unwrap();
+        assert_eq!(keys(&found), [&a.key, &b.key, &c.key, &late.key]);
+
+        let range = DigestQuery {
+            from: at(2),
+            to: at(3),
+            ..query()
+        };
+        assert_eq!(keys(&store.query(&range).await.unwrap()), [&b.key, &c.key]);
+
+        let nodes = DigestQuery {
+            nodes: vec!["a".to_string(), "c".to_string()],
+            ..query()
+        };
+        assert_eq!(
+            keys(&store.query(&nodes).await.unwrap()),
+            [&a.key, &c.key, &late.key]
+        );
+
+        let by_dimension = DigestQuery {
+            dimension: Some("10.0.0.1:443".to_string()),
+            ..query()
+        };
+        assert_eq!(
+            keys(&store.query(&by_dimension).await.unwrap()),
+            [&dimension.key]
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_apps_nodes_and_dimensions() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store_all(
+            &store,
+            &[
+                record("b", 5, None, metric::SRTT, &[1.0]),
+                record("a", 1, None, metric::SRTT, &[1.0]),
+                record("a", 9, Some("10.0.0.1:443"), metric::SRTT, &[1.0]),
+            ],
+        )
+        .await;
+
+        let apps = store.list(&ListBy::Apps).await.unwrap();
+        assert_eq!(apps.len(), 1);
+        assert_eq!(apps[0].name, "app");
+        assert_eq!((apps[0].first_seen, apps[0].last_seen), (at(1), at(9)));
+
+        let app = "app".to_string();
+        let nodes = store
+            .list(&ListBy::Nodes { app: app.clone() })
+            .await
+            .unwrap();
+        let names: Vec<_> = nodes.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+
+        let dimensions = store.list(&ListBy::Dimensions { app }).await.unwrap();
+        assert_eq!(dimensions.len(), 1);
+        assert_eq!(dimensions[0].name, "10.0.0.1:443");
+    }
+
+    #[tokio::test]
+    async fn apps_exist_once_they_store_a_record() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        assert!(!store.app_exists("app").await.unwrap());
+
+        store
+            .store(&record("a", 1, None, metric::SRTT, &[1.0]))
+            .await
+            .unwrap();
+        assert!(store.app_exists("app").await.unwrap());
+        assert!(!store.app_exists("other").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn queries_filter_by_metric() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let srtt = record("a", 1, None, metric::SRTT, &[1.0]);
+        let cwnd = record("a", 1, None, metric::SND_CWND, &[10.0]);
+        store_all(&store, &[srtt.clone(), cwnd.clone()]).await;
+
+        assert_eq!(keys(&store.query(&query()).await.unwrap()), [&srtt.key]);
+
+        let by_metric = DigestQuery {
+            metric: metric::SND_CWND.to_string(),
+            ..query()
+        };
+        let found = store.query(&by_metric).await.unwrap();
+        assert_eq!(keys(&found), [&cwnd.key]);
+        assert_eq!(found[0].metric, metric::SND_CWND);
+    }
+}
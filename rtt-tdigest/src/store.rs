@@ -0,0 +1,64 @@
+use crate::error::Result;
+use crate::record::TDigestRecord;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Most nodes a `DigestQuery` should restrict to, DynamoDB accepts at most
+/// 100 operands in an `IN` condition
+pub const MAX_QUERY_NODES: usize = 100;
+
+/// Selects the records of one app, aggregation level and metric within a time
+/// range
+#[derive(Debug, Clone)]
+pub struct DigestQuery {
+    pub app: String,
+    pub agg_level: String,
+    /// One of the names in `metric`
+    pub metric: String,
+    /// Restrict to these nodes, all nodes of the app when empty, see
+    /// `MAX_QUERY_NODES`
+    pub nodes: Vec<String>,
+    /// Restrict to a single dimension, node-wide records only when unset
+    pub dimension: Option<String>,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+/// What a listing enumerates
+#[derive(Debug, Clone)]
+pub enum ListBy {
+    Apps,
+    Nodes { app: String },
+    Dimensions { app: String },
+}
+
+/// An app, node or dimension with stored digests and the window starts of
+/// its oldest and newest record
+#[derive(Debug, Clone, Serialize)]
+pub struct Listing {
+    pub name: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Backend that persists digest records
+#[async_trait]
+pub trait DigestStore: Send + Sync {
+    /// Persist a record, replacing any existing record with the same key
+    async fn store(&self, record: &TDigestRecord) -> Result<()>;
+
+    /// Return the records matching the query, `from` and `to` inclusive
+    async fn query(&self, query: &DigestQuery) -> Result<Vec<TDigestRecord>>;
+
+    /// Enumerate apps, or the nodes or dimensions of an app, sorted by name
+    async fn list(&self, by: &ListBy) -> Result<Vec<Listing>>;
+
+    /// Whether any record is stored for the app, without listing its nodes
+    async fn app_exists(&self, app: &str) -> Result<bool>;
+
+    /// Rewrite records stored under an outdated key scheme to their
+    /// `TDigestRecord::expected_key`, or on DynamoDB with an outdated
+    /// `series`, returning how many were rewritten
+    async fn migrate_keys(&self) -> Result<usize>;
+}
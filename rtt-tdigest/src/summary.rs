@@ -1,59 +1,141 @@
-use tdigest::TDigest;
+use tdigest::{Centroid, TDigest};
 
 type RttMicros = u32;
 
+/// Number of samples buffered before they are compressed into the digest
+const BATCH_SIZE: usize = 1024;
+
+/// Rtt samples are taken in microseconds and kept in milliseconds
+const MICROS_PER_MILLI: f64 = 1000.0;
+
+/// Digest of rtt samples. Samples are buffered and compressed into the digest
+/// a batch at a time, as merging rebuilds the digest.
 pub struct Summary {
     digest: TDigest,
+    pending: Vec<u32>,
     count: u64,
+    /// Samples are divided by this before they enter the digest
+    scale: f64,
 }
 
 impl Summary {
     /// Return a RttSummary with 100 centroids
     pub fn new() -> Self {
+        Self::with_compression(100)
+    }
+
+    /// Return a RttSummary keeping at most `max_centroids` centroids, more
+    /// centroids give more accurate quantiles at the cost of larger digests
+    pub fn with_compression(max_centroids: usize) -> Self {
+        Self::with_scale(max_centroids, MICROS_PER_MILLI)
+    }
+
+    /// Return a summary of samples of another metric, which are divided by
+    /// `scale` before entering the digest, e.g. 1.0 keeps them as is
+    pub fn with_scale(max_centroids: usize, scale: f64) -> Self {
         Summary {
-            digest: TDigest::new_with_size(100),
+            digest: TDigest::new_with_size(max_centroids),
+            pending: Vec::new(),
             count: 0,
+            scale,
         }
     }
 
     /// Add a rtt measurement to the digest
     pub fn add_rtt(&mut self, rtt: RttMicros) {
-        let rtt_ms = rtt as f64 / 1000.0;
-        self.digest = self.digest.merge_unsorted(vec![rtt_ms]);
+        self.add_value(rtt);
+    }
+
+    /// Add a sample of a summary created with `with_scale` to the digest
+    pub fn add_value(&mut self, value: u32) {
+        self.pending.push(value);
         self.count += 1;
+
+        if self.pending.len() >= BATCH_SIZE {
+            self.flush();
+        }
     }
 
+    /// Add several rtt measurements to the digest
+    pub fn add_batch(&mut self, rtts: &[RttMicros]) {
+        self.pending.extend_from_slice(rtts);
+        self.count += rtts.len() as u64;
+
+        if self.pending.len() >= BATCH_SIZE {
+            self.flush();
+        }
+    }
+
+    /// Add rtt measurements counted into histogram buckets, as pairs of a
+    /// value representing the bucket and the number of samples in it, in
+    /// ascending order of value
+    pub fn add_histogram(&mut self, buckets: &[(RttMicros, u64)]) {
+        let centroids: Vec<Centroid> = buckets
+            .iter()
+            .filter(|(_, samples)| *samples > 0)
+            .map(|&(rtt, samples)| Centroid::new(rtt as f64 / self.scale, samples as f64))
+            .collect();
+        let (Some(min), Some(max)) = (centroids.first(), centroids.last()) else {
+            return;
+        };
+
+        let count: f64 = centroids.iter().map(|c| c.weight()).sum();
+        let sum = centroids.iter().map(|c| c.mean() * c.weight()).sum();
+        let (min, max) = (min.mean(), max.mean());
+        let max_size = centroids.len();
+        let histogram = TDigest::new(centroids, sum, count, max, min, max_size);
+
+        self.flush();
+        // the first digest's compression is kept
+        self.digest = TDigest::merge_digests(vec![self.digest.clone(), histogram]);
+        self.count += count as u64;
+    }
+
+    /// Compress the buffered samples into the digest
+    pub fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            self.digest = self.digest.merge_unsorted(self.scaled(&self.pending));
+            self.pending.clear();
+        }
+    }
+
+    /// Digest of every sample added, including those not yet flushed
     pub fn digest(&self) -> TDigest {
-        self.digest.clone()
+        match self.pending.is_empty() {
+            true => self.digest.clone(),
+            false => self.digest.merge_unsorted(self.scaled(&self.pending)),
+        }
     }
 
     /// Returns the p99 quantile of the given rtt samples
-    pub fn p99(&self) -> f64 {
+    pub fn p99(&mut self) -> f64 {
         self.quantile(0.99)
     }
 
     /// Returns the p95 quantile of the given rtt samples
-    pub fn p95(&self) -> f64 {
+    pub fn p95(&mut self) -> f64 {
         self.quantile(0.95)
     }
 
     /// Returns the p90 quantile of the given rtt samples
-    pub fn p90(&self) -> f64 {
+    pub fn p90(&mut self) -> f64 {
         self.quantile(0.90)
     }
 
     /// Returns the p75 quantile of the given rtt samples
-    pub fn p75(&self) -> f64 {
+    pub fn p75(&mut self) -> f64 {
         self.quantile(0.75)
     }
 
     /// Returns the p50 quantile of the given rtt samples
-    pub fn p50(&self) -> f64 {
+    pub fn p50(&mut self) -> f64 {
         self.quantile(0.50)
     }
 
-    /// Return a quantile 0.0->1.0 e.g. p99 (0.99), p90 (.90)
-    fn quantile(&self, q: f64) -> f64 {
+    /// Return a quantile 0.0->1.0 e.g. p99 (0.99), p90 (.90). Pending samples
+    /// are flushed first, so reading several quantiles merges them only once.
+    fn quantile(&mut self, q: f64) -> f64 {
+        self.flush();
         self.digest.estimate_quantile(q)
     }
 
@@ -61,6 +143,13 @@ impl Summary {
     pub fn count(&self) -> u64 {
         self.count
     }
+
+    fn scaled(&self, values: &[u32]) -> Vec<f64> {
+        values
+            .iter()
+            .map(|&value| value as f64 / self.scale)
+            .collect()
+    }
 }
 
 impl Default for Summary {
@@ -68,3 +157,87 @@ impl Default for Summary {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtts() -> Vec<RttMicros> {
+        (1..=500).map(|ms| ms * 1000).collect()
+    }
+
+    #[test]
+    fn pending_samples_read_the_same_as_flushed_ones() {
+        let mut pending = Summary::new();
+        let mut flushed = Summary::new();
+        for &rtt in &rtts() {
+            pending.add_rtt(rtt);
+            flushed.add_rtt(rtt);
+        }
+        flushed.flush();
+
+        assert_eq!(pending.count(), 500);
+        assert_eq!(pending.count(), flushed.count());
+        assert_eq!(pending.digest().count(), flushed.digest().count());
+        assert_eq!(pending.digest().min(), flushed.digest().min());
+        assert_eq!(pending.digest().max(), flushed.digest().max());
+        assert_eq!(pending.p50(), flushed.p50());
+        assert_eq!(pending.p99(), flushed.p99());
+        // reading flushed the samples, reading again gives the same answer
+        assert_eq!(pending.p99(), flushed.p99());
+    }
+
+    #[test]
+    fn batches_match_single_samples() {
+        let mut single = Summary::new();
+        let mut batched = Summary::new();
+        // past BATCH_SIZE so both flush along the way
+        let rtts: Vec<_> = rtts()
+            .into_iter()
+            .cycle()
+            .take(BATCH_SIZE * 2 + 10)
+            .collect();
+        for &rtt in &rtts {
+            single.add_rtt(rtt);
+        }
+        for chunk in rtts.chunks(100) {
+            batched.add_batch(chunk);
+        }
+
+        assert_eq!(single.count(), batched.count());
+        assert_eq!(single.digest().count(), batched.digest().count());
+        assert!((single.p50() - batched.p50()).abs() < 1.0);
+        assert!((single.p99() - batched.p99()).abs() < 1.0);
+    }
+
+    #[test]
+    fn histogram_buckets_are_weighted_by_their_samples() {
+        let mut summary = Summary::new();
+        summary.add_histogram(&[(1000, 1), (2000, 0), (4000, 9)]);
+
+        assert_eq!(summary.count(), 10);
+        let digest = summary.digest();
+        assert_eq!(digest.count(), 10.0);
+        assert_eq!(digest.min(), 1.0);
+        assert_eq!(digest.max(), 4.0);
+        // nine of ten samples are in the 4ms bucket, unweighted the median
+        // would fall between 1 and 4
+        assert!(summary.p50() > 3.5, "p50 {}", summary.p50());
+
+        // samples added before and after are merged with the buckets
+        summary.add_rtt(8000);
+        assert_eq!(summary.count(), 11);
+        assert_eq!(summary.digest().max(), 8.0);
+
+        summary.add_histogram(&[]);
+        assert_eq!(summary.count(), 11);
+    }
+
+    #[test]
+    fn scale_divides_samples() {
+        let mut summary = Summary::with_scale(100, 1.0);
+        summary.add_value(40);
+
+        assert_eq!(summary.p50(), 40.0);
+    }
+}